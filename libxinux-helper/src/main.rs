@@ -1,10 +1,18 @@
 use anyhow::{anyhow, Result};
 use clap::{self, FromArgMatches, Subcommand};
-use signal_hook::{consts::SIGINT, iterator::Signals};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
 use std::{
     fs::{self, File},
-    io::{self, Read, Write},
-    process::Command,
+    io::{self, BufRead, Read, Write},
+    os::unix::process::CommandExt,
+    process::{Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
     thread,
 };
 
@@ -15,6 +23,10 @@ enum SubCommands {
         #[arg(short, long)]
         output: String,
 
+        /// Cancel when standard input is closed or receives more data
+        #[arg(long)]
+        watch_stdin: bool,
+
         /// How many generations to keep
         #[arg(short, long)]
         generations: Option<u32>,
@@ -27,6 +39,10 @@ enum SubCommands {
         #[arg(short, long)]
         flake: String,
 
+        /// Cancel when standard input is closed or receives more data
+        #[arg(long)]
+        watch_stdin: bool,
+
         /// How many generations to keep
         #[arg(short, long)]
         generations: Option<u32>,
@@ -35,6 +51,10 @@ enum SubCommands {
         arguments: Vec<String>,
     },
     Rebuild {
+        /// Cancel when standard input is closed or receives more data
+        #[arg(long)]
+        watch_stdin: bool,
+
         /// How many generations to keep
         #[arg(short, long)]
         generations: Option<u32>,
@@ -47,6 +67,10 @@ enum SubCommands {
         #[arg(short, long)]
         output: String,
 
+        /// Cancel when standard input is closed or receives more data
+        #[arg(long)]
+        watch_stdin: bool,
+
         /// How many generations to keep
         #[arg(short, long)]
         generations: Option<u32>,
@@ -59,6 +83,10 @@ enum SubCommands {
         #[arg(short, long)]
         flake: String,
 
+        /// Cancel when standard input is closed or receives more data
+        #[arg(long)]
+        watch_stdin: bool,
+
         /// How many generations to keep
         #[arg(short, long)]
         generations: Option<u32>,
@@ -67,6 +95,10 @@ enum SubCommands {
         arguments: Vec<String>,
     },
     RebuildHome {
+        /// Cancel when standard input is closed or receives more data
+        #[arg(long)]
+        watch_stdin: bool,
+
         /// How many generations to keep
         #[arg(short, long)]
         generations: Option<u32>,
@@ -83,13 +115,23 @@ fn main() {
         .map_err(|err| err.exit())
         .unwrap();
 
+    // Restore the original configuration file and stop the running command on any
+    // termination request
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).unwrap();
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            cancel();
+        }
+    });
+
     match derived_subcommands {
         SubCommands::Config {
             output,
+            watch_stdin,
             generations,
             arguments,
         } => {
-            match write_file(&output, arguments, generations) {
+            match write_file(&output, watch_stdin, arguments, generations) {
                 Ok(_) => (),
                 Err(err) => {
                     eprintln!("{}", err);
@@ -99,31 +141,44 @@ fn main() {
         }
         SubCommands::Update {
             flake,
+            watch_stdin,
             generations,
             arguments,
-        } => match update(&flake, arguments, generations) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("{}", err);
-                std::process::exit(1);
+        } => {
+            if watch_stdin {
+                watch_stdin_for_cancel();
             }
-        },
+            match update(&flake, arguments, generations) {
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
         SubCommands::Rebuild {
+            watch_stdin,
             generations,
             arguments,
-        } => match rebuild(arguments, generations) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("{}", err);
-                std::process::exit(1);
+        } => {
+            if watch_stdin {
+                watch_stdin_for_cancel();
+            }
+            match rebuild(arguments, generations) {
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
             }
-        },
+        }
         SubCommands::ConfigHome {
             output,
+            watch_stdin,
             generations,
             arguments,
         } => {
-            match write_file_home(&output, arguments, generations) {
+            match write_file_home(&output, watch_stdin, arguments, generations) {
                 Ok(_) => (),
                 Err(err) => {
                     eprintln!("{}", err);
@@ -133,60 +188,146 @@ fn main() {
         }
         SubCommands::UpdateHome {
             flake,
+            watch_stdin,
             generations,
             arguments,
-        } => match update_home(&flake, arguments, generations) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("{}", err);
-                std::process::exit(1);
+        } => {
+            if watch_stdin {
+                watch_stdin_for_cancel();
             }
-        },
+            match update_home(&flake, arguments, generations) {
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
         SubCommands::RebuildHome {
+            watch_stdin,
             generations,
             arguments,
-        } => match rebuild_home(arguments, generations) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("{}", err);
-                std::process::exit(1);
+        } => {
+            if watch_stdin {
+                watch_stdin_for_cancel();
+            }
+            match rebuild_home(arguments, generations) {
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
             }
-        },
+        }
     }
 }
 
-fn write_file(path: &str, args: Vec<String>, generations: Option<u32>) -> Result<()> {
-    let backup = fs::read_to_string(path)?;
+/// Set once a cancellation was requested
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// Process group of the currently running child process
+static CHILD: Mutex<Option<u32>> = Mutex::new(None);
+/// Path and original contents of the configuration file being replaced
+static BACKUP: Mutex<Option<(String, String)>> = Mutex::new(None);
 
-    let stdin = io::stdin();
-    let mut buf = String::new();
-    stdin.lock().read_to_string(&mut buf)?;
-
-    // If the user sends a SIGINT, restore the original configuration file
-    {
-        let mut signals = Signals::new(&[SIGINT]).unwrap();
-        let p = path.to_string();
-        let b = backup.clone();
-        let handle = move || {
-            let mut file = File::create(&p).unwrap();
-            write!(file, "{}", &b).unwrap();
-        };
-        thread::spawn(move || {
-            for sig in signals.forever() {
-                if sig == SIGINT {
-                    handle();
-                    std::process::exit(1);
-                }
-            }
-        });
+/// Stop the running child process, restore the original configuration file and exit
+fn cancel() {
+    if CANCELLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(pid) = *CHILD.lock().unwrap() {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+        }
+    }
+    if let Err(err) = restore_backup() {
+        eprintln!("Failed to restore configuration: {}", err);
+    }
+    std::process::exit(130);
+}
+
+/// Write back the original configuration file, if it was replaced
+fn restore_backup() -> Result<()> {
+    if let Some((path, backup)) = BACKUP.lock().unwrap().take() {
+        phase("restore-config");
+        let mut file = File::create(path)?;
+        write!(file, "{}", &backup)?;
+    }
+    Ok(())
+}
+
+/// Forget the original configuration file once the new one is in use, so that it is
+/// no longer restored
+fn discard_backup() {
+    BACKUP.lock().unwrap().take();
+}
+
+/// Cancel once standard input is closed or more data arrives
+fn watch_stdin_for_cancel() {
+    thread::spawn(|| {
+        let _ = io::stdin().read(&mut [0; 1]);
+        cancel();
+    });
+}
+
+/// Read the new configuration file from standard input. With `watch_stdin`, the
+/// configuration ends at a NUL byte and the rest of the input is watched for cancellation.
+fn read_config(watch_stdin: bool) -> Result<String> {
+    let mut buf = Vec::new();
+    if watch_stdin {
+        io::stdin().lock().read_until(0, &mut buf)?;
+        if buf.pop() != Some(0) {
+            // Standard input was closed before the configuration was complete
+            cancel();
+        }
+        watch_stdin_for_cancel();
+    } else {
+        io::stdin().lock().read_to_end(&mut buf)?;
     }
+    Ok(String::from_utf8(buf)?)
+}
 
+/// Run `cmd` in its own process group, so that [cancel] can stop it together with its children
+fn run_child(cmd: &mut Command) -> Result<ExitStatus> {
+    let mut child = cmd.process_group(0).spawn()?;
+    *CHILD.lock().unwrap() = Some(child.id());
+    if CANCELLED.load(Ordering::SeqCst) {
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGTERM);
+        }
+    }
+    let status = child.wait();
+    *CHILD.lock().unwrap() = None;
+    if CANCELLED.load(Ordering::SeqCst) {
+        // The cancelling thread exits the process once the backup is restored
+        loop {
+            thread::park();
+        }
+    }
+    Ok(status?)
+}
+
+/// Print a phase marker that libxinux turns into a progress event
+fn phase(name: &str) {
+    eprintln!("@libxinux {{\"phase\":\"{}\"}}", name);
+}
+
+fn write_file(
+    path: &str,
+    watch_stdin: bool,
+    args: Vec<String>,
+    generations: Option<u32>,
+) -> Result<()> {
+    let backup = fs::read_to_string(path)?;
+
+    let buf = read_config(watch_stdin)?;
+    *BACKUP.lock().unwrap() = Some((path.to_string(), backup));
+
+    phase("write-config");
     let mut file = File::create(path)?;
     write!(file, "{}", &buf)?;
 
     if rebuild(args, generations).is_err() {
-        let mut file = File::create(path)?;
-        write!(file, "{}", &backup)?;
+        restore_backup()?;
         Err(anyhow!("Failed to rebuild"))
     } else {
         Ok(())
@@ -194,13 +335,8 @@ fn write_file(path: &str, args: Vec<String>, generations: Option<u32>) -> Result
 }
 
 fn update(path: &str, args: Vec<String>, generations: Option<u32>) -> Result<()> {
-    let mut cmd = Command::new("nix")
-        .arg("flake")
-        .arg("update")
-        .arg("--flake")
-        .arg(path)
-        .spawn()?;
-    let x = cmd.wait()?;
+    phase("update-flake");
+    let x = run_child(Command::new("nix").args(["flake", "update", "--flake", path]))?;
     if !x.success() {
         eprintln!(
             "nix flake update failed with exit code {}",
@@ -212,21 +348,23 @@ fn update(path: &str, args: Vec<String>, generations: Option<u32>) -> Result<()>
 }
 
 fn rebuild(args: Vec<String>, generations: Option<u32>) -> Result<()> {
-    let mut cmd = Command::new("nixos-rebuild").args(args).spawn()?;
-    let x = cmd.wait()?;
+    phase("rebuild");
+    let x = run_child(Command::new("nixos-rebuild").args(args))?;
     if !x.success() {
         eprintln!("nixos-rebuild failed with exit code {}", x.code().unwrap());
         return Err(anyhow!("nixos-rebuild failed"));
     }
+    discard_backup();
     if let Some(g) = generations {
         if g > 0 {
-            let mut cmd = Command::new("nix-env")
-                .arg("--delete-generations")
-                .arg("-p")
-                .arg("/nix/var/nix/profiles/system")
-                .arg(&format!("+{}", g))
-                .spawn()?;
-            let x = cmd.wait()?;
+            phase("delete-generations");
+            let x = run_child(
+                Command::new("nix-env")
+                    .arg("--delete-generations")
+                    .arg("-p")
+                    .arg("/nix/var/nix/profiles/system")
+                    .arg(format!("+{}", g)),
+            )?;
             if !x.success() {
                 eprintln!(
                     "nix-env --delete-generations failed with exit code {}",
@@ -239,38 +377,23 @@ fn rebuild(args: Vec<String>, generations: Option<u32>) -> Result<()> {
     Ok(())
 }
 
-fn write_file_home(path: &str, args: Vec<String>, generations: Option<u32>) -> Result<()> {
+fn write_file_home(
+    path: &str,
+    watch_stdin: bool,
+    args: Vec<String>,
+    generations: Option<u32>,
+) -> Result<()> {
     let backup = fs::read_to_string(path)?;
 
-    let stdin = io::stdin();
-    let mut buf = String::new();
-    stdin.lock().read_to_string(&mut buf)?;
-
-    // If the user sends a SIGINT, restore the original configuration file
-    {
-        let mut signals = Signals::new(&[SIGINT]).unwrap();
-        let p = path.to_string();
-        let b = backup.clone();
-        let handle = move || {
-            let mut file = File::create(&p).unwrap();
-            write!(file, "{}", &b).unwrap();
-        };
-        thread::spawn(move || {
-            for sig in signals.forever() {
-                if sig == SIGINT {
-                    handle();
-                    std::process::exit(1);
-                }
-            }
-        });
-    }
+    let buf = read_config(watch_stdin)?;
+    *BACKUP.lock().unwrap() = Some((path.to_string(), backup));
 
+    phase("write-config");
     let mut file = File::create(path)?;
     write!(file, "{}", &buf)?;
 
     if rebuild_home(args, generations).is_err() {
-        let mut file = File::create(path)?;
-        write!(file, "{}", &backup)?;
+        restore_backup()?;
         Err(anyhow!("Failed to rebuild"))
     } else {
         Ok(())
@@ -278,13 +401,8 @@ fn write_file_home(path: &str, args: Vec<String>, generations: Option<u32>) -> R
 }
 
 fn update_home(path: &str, args: Vec<String>, generations: Option<u32>) -> Result<()> {
-    let mut cmd = Command::new("nix")
-        .arg("flake")
-        .arg("update")
-        .arg("--flake")
-        .arg(path)
-        .spawn()?;
-    let x = cmd.wait()?;
+    phase("update-flake");
+    let x = run_child(Command::new("nix").args(["flake", "update", "--flake", path]))?;
     if !x.success() {
         eprintln!(
             "nix flake update failed with exit code {}",
@@ -296,24 +414,26 @@ fn update_home(path: &str, args: Vec<String>, generations: Option<u32>) -> Resul
 }
 
 fn rebuild_home(args: Vec<String>, generations: Option<u32>) -> Result<()> {
-    let mut cmd = Command::new("home-manager").args(args).spawn()?;
-    let x = cmd.wait()?;
+    phase("rebuild");
+    let x = run_child(Command::new("home-manager").args(args))?;
     if !x.success() {
         eprintln!("home-manager failed with exit code {}", x.code().unwrap());
         return Err(anyhow!("home-manager failed"));
     }
+    discard_backup();
     if let Some(g) = generations {
         if g > 0 {
-            let mut cmd = Command::new("nix-env")
-                .arg("--delete-generations")
-                .arg("-p")
-                .arg(format!(
-                    "{}/.local/state/nix/profiles/home-manager",
-                    std::env::var("HOME")?
-                ))
-                .arg(&format!("+{}", g))
-                .spawn()?;
-            let x = cmd.wait()?;
+            phase("delete-generations");
+            let x = run_child(
+                Command::new("nix-env")
+                    .arg("--delete-generations")
+                    .arg("-p")
+                    .arg(format!(
+                        "{}/.local/state/nix/profiles/home-manager",
+                        std::env::var("HOME")?
+                    ))
+                    .arg(format!("+{}", g)),
+            )?;
             if !x.success() {
                 eprintln!(
                     "nix-env --delete-generations failed with exit code {}",
@@ -0,0 +1,73 @@
+//! # Backend
+//!
+//! A common interface over the different ways packages can be managed:
+//! NixOS system configuration, home-manager, `nix profile` and `nix-env`.
+
+use crate::{
+    config::configfile::{get_config, get_user_pkg_type, UserPkgType},
+    homemanager::HomeManagerBackend,
+    nixenv::NixEnvBackend,
+    nixos::{AuthMethod, NixosBackend},
+    profile::ProfileBackend,
+    Context, Error, Package, PackageUpdate, Result,
+};
+use async_trait::async_trait;
+
+/// Operations shared by every package manager supported by libxinux.
+#[async_trait(?Send)]
+pub trait PackageBackend {
+    /// List all packages installed through this backend.
+    async fn list(&self) -> Result<Vec<Package>>;
+
+    /// Install the given nixpkgs attributes.
+    async fn install(&self, pkgs: &[&str]) -> Result<()>;
+
+    /// Remove the given nixpkgs attributes.
+    async fn remove(&self, pkgs: &[&str]) -> Result<()>;
+
+    /// List installed packages that have a newer version available.
+    async fn updatable(&self) -> Result<Vec<PackageUpdate>>;
+
+    /// Update the given packages. An empty slice updates everything.
+    /// Declarative backends (NixOS and home-manager) always update the whole configuration.
+    async fn update(&self, pkgs: &[&str]) -> Result<()>;
+}
+
+/// Which set of packages a backend should manage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendScope {
+    /// Packages in `environment.systemPackages`. Only available on NixOS.
+    System,
+    /// Packages installed for the current user.
+    User,
+}
+
+/// Pick the backend matching the current system.
+///
+/// - [System](BackendScope::System) uses the NixOS configuration, and fails on non-NixOS systems.
+/// - [User](BackendScope::User) uses home-manager if a home configuration is set,
+///   otherwise `nix profile` or `nix-env` depending on [get_user_pkg_type].
+pub fn get_backend<'a>(
+    ctx: &'a Context,
+    scope: BackendScope,
+    db: rusqlite::Connection,
+    auth_method: AuthMethod<'a>,
+) -> Result<Box<dyn PackageBackend + 'a>> {
+    match scope {
+        BackendScope::System if ctx.is_nixos() => {
+            Ok(Box::new(NixosBackend::new(ctx, db, auth_method)))
+        }
+        BackendScope::System => Err(Error::Config(
+            "System packages are only available on NixOS".to_string(),
+        )),
+        BackendScope::User => {
+            if get_config(ctx).is_ok_and(|config| config.homeconfig.is_some()) {
+                return Ok(Box::new(HomeManagerBackend::new(ctx, db)));
+            }
+            match get_user_pkg_type(ctx) {
+                UserPkgType::Profile => Ok(Box::new(ProfileBackend::new(ctx))),
+                UserPkgType::Env => Ok(Box::new(NixEnvBackend::new(ctx, db))),
+            }
+        }
+    }
+}
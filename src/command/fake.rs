@@ -0,0 +1,194 @@
+use super::{CommandOutput, CommandRunner, CommandSpec};
+use crate::Result;
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// Scriptable [CommandRunner] that never spawns a process.
+///
+/// Responses are matched against the program name and the leading arguments of each
+/// command, in the order they were registered. Every command is recorded and can be
+/// inspected with [calls](FakeRunner::calls).
+///
+/// ```
+/// use libxinux::command::{CommandOutput, FakeRunner};
+///
+/// let runner = FakeRunner::new().on(
+///     "nix",
+///     &["profile", "list", "--json"],
+///     CommandOutput::with_stdout(r#"{"elements": {}, "version": 3}"#),
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct FakeRunner {
+    responses: Vec<(String, Vec<String>, CommandOutput)>,
+    calls: Mutex<Vec<CommandSpec>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Respond with `output` to any run of `program` whose arguments start with `args`.
+    pub fn on(mut self, program: &str, args: &[&str], output: CommandOutput) -> Self {
+        self.responses.push((
+            program.to_string(),
+            args.iter().map(|x| x.to_string()).collect(),
+            output,
+        ));
+        self
+    }
+
+    /// Commands run so far, in order.
+    pub fn calls(&self) -> Vec<CommandSpec> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn respond(&self, cmd: &CommandSpec) -> Result<CommandOutput> {
+        self.calls.lock().unwrap().push(cmd.clone());
+        self.responses
+            .iter()
+            .find(|(program, args, _)| *program == cmd.program && cmd.args.starts_with(args))
+            .map(|(_, _, output)| output.clone())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No fake response for `{}`", cmd),
+                )
+                .into()
+            })
+    }
+}
+
+#[async_trait]
+impl CommandRunner for FakeRunner {
+    async fn run(&self, cmd: &CommandSpec) -> Result<CommandOutput> {
+        self.respond(cmd)
+    }
+
+    fn run_blocking(&self, cmd: &CommandSpec) -> Result<CommandOutput> {
+        self.respond(cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Context, PackageAttr};
+    use std::sync::Arc;
+
+    fn context(runner: &Arc<FakeRunner>) -> Context {
+        Context::builder()
+            .runner(runner.clone())
+            .arch("x86_64-linux")
+            .home("/home/test")
+            .is_nixos(false)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn profile_list() {
+        let runner = Arc::new(FakeRunner::new().on(
+            "nix",
+            &["profile", "list", "--json"],
+            CommandOutput::with_stdout(
+                r#"{
+                    "elements": {
+                        "hello": {
+                            "active": true,
+                            "attrPath": "legacyPackages.x86_64-linux.hello",
+                            "originalUrl": "flake:nixpkgs",
+                            "storePaths": ["/nix/store/0000000000000000000000000000000a-hello-2.12.1"]
+                        },
+                        "helix": {
+                            "active": true,
+                            "attrPath": "packages.x86_64-linux.default",
+                            "originalUrl": "github:helix-editor/helix",
+                            "storePaths": ["/nix/store/0000000000000000000000000000000b-helix-24.07"]
+                        }
+                    },
+                    "version": 3
+                }"#,
+            ),
+        ));
+        let ctx = context(&runner);
+        let mut pkgs = crate::profile::list::list(&ctx).unwrap();
+        pkgs.sort_by(|a, b| a.profile_name.cmp(&b.profile_name));
+        assert_eq!(pkgs.len(), 2);
+
+        assert_eq!(pkgs[0].profile_name.as_deref(), Some("helix"));
+        assert_eq!(pkgs[0].pname.as_deref(), Some("helix"));
+        assert_eq!(pkgs[0].version.as_deref(), Some("24.07"));
+        assert_eq!(
+            pkgs[0].attr,
+            PackageAttr::External {
+                flake: "github:helix-editor/helix".parse().unwrap(),
+                attr: "packages.x86_64-linux.default".to_string(),
+                outputs: None,
+            }
+        );
+
+        assert_eq!(pkgs[1].profile_name.as_deref(), Some("hello"));
+        assert_eq!(pkgs[1].pname.as_deref(), Some("hello"));
+        assert_eq!(pkgs[1].version.as_deref(), Some("2.12.1"));
+        assert_eq!(
+            pkgs[1].attr,
+            PackageAttr::NixPkgs {
+                attr: "hello".to_string(),
+                outputs: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn nixenv_list() {
+        let runner = Arc::new(FakeRunner::new().on(
+            "nix-env",
+            &["-q"],
+            CommandOutput::with_stdout(
+                r#"{
+                    "hello-2.12.1": {
+                        "name": "hello-2.12.1",
+                        "pname": "hello",
+                        "version": "2.12.1",
+                        "outputs": {"out": "/nix/store/0000000000000000000000000000000a-hello-2.12.1"}
+                    },
+                    "python3-3.12.4": {
+                        "name": "python3-3.12.4",
+                        "pname": "python3",
+                        "version": "3.12.4",
+                        "outputs": {"out": "/nix/store/0000000000000000000000000000000c-python3-3.12.4"}
+                    }
+                }"#,
+            ),
+        ));
+        let ctx = context(&runner);
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE pkgs (attribute TEXT, pname TEXT, version TEXT);
+             CREATE TABLE meta (attribute TEXT, description TEXT, long_description TEXT,
+                 broken INTEGER, insecure INTEGER, unfree INTEGER);
+             INSERT INTO pkgs VALUES ('hello', 'hello', '2.12.1'),
+                 ('python3', 'python3', '3.12.4'), ('python312', 'python3', '3.12.4');",
+        )
+        .unwrap();
+
+        let pkgs = crate::nixenv::list::list(&ctx, &db).await.unwrap();
+        // python3 is left out, as two attributes provide it
+        assert_eq!(pkgs.len(), 1);
+        assert_eq!(
+            pkgs[0].attr,
+            PackageAttr::NixPkgs {
+                attr: "hello".to_string(),
+                outputs: None,
+            }
+        );
+        assert_eq!(pkgs[0].pname.as_deref(), Some("hello"));
+        assert_eq!(pkgs[0].version.as_deref(), Some("2.12.1"));
+        assert_eq!(
+            runner.calls()[0].to_string(),
+            "nix-env -q --out-path --installed --json"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! # Command
+//!
+//! Every external program libxinux runs (`nix`, `nix-env`, `nix-store`, `nix-channel`,
+//! `nixos-version`, `libxinux-helper`, ...) goes through a [CommandRunner].
+//! [SystemRunner] spawns real processes, while [FakeRunner] returns canned output
+//! so that the parsing logic can be exercised without a Nix installation.
+
+mod fake;
+mod system;
+
+pub use fake::FakeRunner;
+pub use system::SystemRunner;
+
+use crate::Result;
+use async_trait::async_trait;
+use std::fmt;
+
+pub use tokio_util::sync::CancellationToken;
+
+/// Description of a program invocation.
+#[derive(Debug, Clone, Default)]
+pub struct CommandSpec {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Data written to the standard input of the process.
+    pub stdin: Option<Vec<u8>>,
+    /// Let the process inherit the standard output and error of the current process
+    /// instead of capturing them.
+    pub interactive: bool,
+    /// Token that terminates the process when cancelled.
+    pub cancel: Option<CancellationToken>,
+}
+
+impl CommandSpec {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn stdin(mut self, stdin: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(stdin.into());
+        self
+    }
+
+    pub fn interactive(mut self) -> Self {
+        self.interactive = true;
+        self
+    }
+
+    /// Terminate the process tree and return [Cancelled](crate::Error::Cancelled) once `cancel`
+    /// is cancelled. If [stdin](CommandSpec::stdin) is set, the pipe is kept open until the
+    /// process exits, and closed on cancellation.
+    pub fn cancel_on(mut self, cancel: Option<&CancellationToken>) -> Self {
+        self.cancel = cancel.cloned();
+        self
+    }
+}
+
+impl CommandSpec {
+    /// Pass `config` to `libxinux-helper` on standard input. When `cancel` is set, the
+    /// helper is expected to have been given [helper_watch_args], so that it restores
+    /// its backup once the pipe is closed.
+    pub(crate) fn helper_input(
+        self,
+        config: Option<String>,
+        cancel: Option<&CancellationToken>,
+    ) -> Self {
+        let cmd = self.cancel_on(cancel);
+        match (config, cancel) {
+            (Some(config), Some(_)) => {
+                // The helper reads the configuration up to a NUL byte and keeps watching afterwards
+                let mut data = config.into_bytes();
+                data.push(0);
+                cmd.stdin(data)
+            }
+            (Some(config), None) => cmd.stdin(config),
+            (None, Some(_)) => cmd.stdin(vec![]),
+            (None, None) => cmd,
+        }
+    }
+}
+
+/// Arguments making `libxinux-helper` watch its standard input for cancellation.
+pub(crate) fn helper_watch_args(cancel: Option<&CancellationToken>) -> Vec<&'static str> {
+    if cancel.is_some() {
+        vec!["--watch-stdin"]
+    } else {
+        vec![]
+    }
+}
+
+impl fmt::Display for CommandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of a finished process.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Exit code of the process, or `None` if it was terminated by a signal.
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CommandOutput {
+    /// Successful output with the given standard output.
+    pub fn with_stdout(stdout: impl Into<Vec<u8>>) -> Self {
+        Self {
+            code: Some(0),
+            stdout: stdout.into(),
+            stderr: vec![],
+        }
+    }
+
+    /// Failed output with the given exit code and standard error.
+    pub fn with_failure(code: i32, stderr: impl Into<Vec<u8>>) -> Self {
+        Self {
+            code: Some(code),
+            stdout: vec![],
+            stderr: stderr.into(),
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    pub fn stdout_string(&self) -> Result<String> {
+        Ok(String::from_utf8(self.stdout.clone())?)
+    }
+}
+
+impl From<std::process::Output> for CommandOutput {
+    fn from(output: std::process::Output) -> Self {
+        Self {
+            code: output.status.code(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        }
+    }
+}
+
+/// Runs external programs on behalf of libxinux.
+#[async_trait]
+pub trait CommandRunner: Send + Sync {
+    /// Run the command to completion without blocking the async runtime.
+    async fn run(&self, cmd: &CommandSpec) -> Result<CommandOutput>;
+
+    /// Run the command to completion, blocking the current thread.
+    fn run_blocking(&self, cmd: &CommandSpec) -> Result<CommandOutput>;
+
+    /// Run the command to completion, calling `on_line` for every line of output
+    /// as soon as it is printed.
+    ///
+    /// The default implementation calls `on_line` after the command has finished.
+    async fn run_streaming(
+        &self,
+        cmd: &CommandSpec,
+        on_line: &mut (dyn for<'l> FnMut(&'l str) + Send),
+    ) -> Result<CommandOutput> {
+        let output = self.run(cmd).await?;
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            on_line(line);
+        }
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            on_line(line);
+        }
+        Ok(output)
+    }
+}
@@ -0,0 +1,242 @@
+use super::{CommandOutput, CommandRunner, CommandSpec};
+use crate::{Error, Result};
+use async_trait::async_trait;
+use std::{io::Write, os::unix::process::CommandExt, process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Child,
+};
+
+/// How long a cancelled process gets to clean up before it is killed.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// [CommandRunner] spawning real processes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRunner;
+
+impl SystemRunner {
+    fn stdio(cmd: &CommandSpec) -> (Stdio, Stdio, Stdio) {
+        let stdin = if cmd.stdin.is_some() {
+            Stdio::piped()
+        } else if cmd.interactive {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        };
+        if cmd.interactive {
+            (stdin, Stdio::inherit(), Stdio::inherit())
+        } else {
+            (stdin, Stdio::piped(), Stdio::piped())
+        }
+    }
+
+    async fn execute(
+        cmd: &CommandSpec,
+        on_line: &mut (dyn for<'l> FnMut(&'l str) + Send),
+    ) -> Result<CommandOutput> {
+        let (stdin, stdout, stderr) = Self::stdio(cmd);
+        let mut command = std::process::Command::new(&cmd.program);
+        command
+            .args(&cmd.args)
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(stderr);
+        if cmd.cancel.is_some() && !cmd.interactive {
+            // Run in a separate process group so the whole tree can be terminated
+            command.process_group(0);
+        }
+        let mut child = tokio::process::Command::from(command).spawn()?;
+
+        // Standard input is written while the output is read, so neither pipe can fill up
+        // and block the process. When cancellation is possible, it stays open until the
+        // process exits or is cancelled. Closing it tells `libxinux-helper --watch-stdin` to
+        // stop, even when it runs as root and cannot be signalled.
+        let stdin_task = match &cmd.stdin {
+            Some(data) => {
+                let mut pipe = child
+                    .stdin
+                    .take()
+                    .ok_or_else(|| std::io::Error::other("stdin not available"))?;
+                let data = data.clone();
+                let cancel = cmd.cancel.clone();
+                Some(tokio::spawn(async move {
+                    pipe.write_all(&data).await?;
+                    if let Some(token) = cancel {
+                        token.cancelled().await;
+                    }
+                    Ok::<_, std::io::Error>(())
+                }))
+            }
+            None => None,
+        };
+
+        let cancelled = async {
+            match &cmd.cancel {
+                Some(token) => token.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        let output = tokio::select! {
+            output = Self::collect(&mut child, on_line) => Some(output),
+            _ = cancelled => None,
+        };
+        if let Some(task) = stdin_task {
+            task.abort();
+            // A process may exit without reading all of its input, its exit code tells
+            // whether that is a failure
+            if let Ok(Err(err)) = task.await {
+                if err.kind() != std::io::ErrorKind::BrokenPipe {
+                    return Err(err.into());
+                }
+            }
+        }
+        match output {
+            Some(output) => output,
+            None => {
+                Self::terminate(&mut child).await;
+                Err(Error::Cancelled)
+            }
+        }
+    }
+
+    /// Read the output of `child` until it exits.
+    async fn collect(
+        child: &mut Child,
+        on_line: &mut (dyn for<'l> FnMut(&'l str) + Send),
+    ) -> Result<CommandOutput> {
+        let mut output = CommandOutput::default();
+        let mut stdout = child.stdout.take().map(BufReader::new);
+        let mut stderr = child.stderr.take().map(BufReader::new);
+        let (mut stdout_line, mut stderr_line) = (vec![], vec![]);
+        while stdout.is_some() || stderr.is_some() {
+            tokio::select! {
+                read = read_line(&mut stdout, &mut stdout_line), if stdout.is_some() => {
+                    if read? == 0 {
+                        stdout = None;
+                    } else {
+                        on_line(String::from_utf8_lossy(&stdout_line).trim_end_matches('\n'));
+                        output.stdout.append(&mut stdout_line);
+                    }
+                }
+                read = read_line(&mut stderr, &mut stderr_line), if stderr.is_some() => {
+                    if read? == 0 {
+                        stderr = None;
+                    } else {
+                        on_line(String::from_utf8_lossy(&stderr_line).trim_end_matches('\n'));
+                        output.stderr.append(&mut stderr_line);
+                    }
+                }
+            }
+        }
+        output.code = child.wait().await?.code();
+        Ok(output)
+    }
+
+    /// Ask the process group of `child` to terminate, and kill it if it does not
+    /// exit within [CANCEL_GRACE_PERIOD].
+    async fn terminate(child: &mut Child) {
+        let Some(pid) = child.id() else {
+            return;
+        };
+        // Signalling fails for processes running as another user (e.g. through pkexec),
+        // those are expected to exit once their standard input is closed
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+        }
+        if tokio::time::timeout(CANCEL_GRACE_PERIOD, child.wait())
+            .await
+            .is_err()
+        {
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
+            let _ = child.kill().await;
+        }
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut Option<R>,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<usize> {
+    match reader {
+        Some(reader) => reader.read_until(b'\n', buf).await,
+        None => Ok(0),
+    }
+}
+
+#[async_trait]
+impl CommandRunner for SystemRunner {
+    async fn run(&self, cmd: &CommandSpec) -> Result<CommandOutput> {
+        Self::execute(cmd, &mut |_| {}).await
+    }
+
+    async fn run_streaming(
+        &self,
+        cmd: &CommandSpec,
+        on_line: &mut (dyn for<'l> FnMut(&'l str) + Send),
+    ) -> Result<CommandOutput> {
+        Self::execute(cmd, on_line).await
+    }
+
+    fn run_blocking(&self, cmd: &CommandSpec) -> Result<CommandOutput> {
+        let (stdin, stdout, stderr) = Self::stdio(cmd);
+        let mut child = std::process::Command::new(&cmd.program)
+            .args(&cmd.args)
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn()?;
+        let stdin_thread = match &cmd.stdin {
+            Some(data) => {
+                let mut pipe = child
+                    .stdin
+                    .take()
+                    .ok_or_else(|| std::io::Error::other("stdin not available"))?;
+                let data = data.clone();
+                Some(std::thread::spawn(move || pipe.write_all(&data)))
+            }
+            None => None,
+        };
+        let output = child.wait_with_output()?;
+        if let Some(Ok(Err(err))) = stdin_thread.map(|x| x.join()) {
+            if err.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(err.into());
+            }
+        }
+        Ok(output.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// More than fits in a pipe buffer, so writing it all before reading would block.
+    fn large_input() -> Vec<u8> {
+        (0..1 << 20).map(|i| b'a' + (i % 26) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn writes_stdin_while_reading_output() {
+        let cmd = CommandSpec::new("cat").stdin(large_input());
+        let output = SystemRunner.run(&cmd).await.unwrap();
+        assert!(output.success());
+        assert_eq!(output.stdout, large_input());
+    }
+
+    #[test]
+    fn writes_stdin_while_reading_output_blocking() {
+        let cmd = CommandSpec::new("cat").stdin(large_input());
+        let output = SystemRunner.run_blocking(&cmd).unwrap();
+        assert!(output.success());
+        assert_eq!(output.stdout, large_input());
+    }
+
+    #[tokio::test]
+    async fn no_stdin_reads_nothing() {
+        let output = SystemRunner.run(&CommandSpec::new("cat")).await.unwrap();
+        assert!(output.success());
+        assert!(output.stdout.is_empty());
+    }
+}
@@ -1,12 +1,23 @@
-use crate::{CONFIG, CONFIGDIR, HOME, SYSCONFIG};
-use anyhow::{anyhow, Context, Result};
+use crate::{Context, Error, Result, SYSCONFIG};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fs::{self, File},
     io::{BufReader, Write},
     path::{Path, PathBuf},
 };
 
+/// Metadata database service used when no other is configured.
+pub const DEFAULT_DATABASE_URL: &str = "https://api.snowflakeos.org/libsnow";
+/// Store path lookup service used when no other is configured.
+pub const DEFAULT_STOREBATCH_URL: &str = "https://api.snowflakeos.org/v0/storebatch";
+/// Releases whose updates are checked on another branch than `nixos-{release}`.
+pub const DEFAULT_RELEASE_BRANCHES: &[(&str, &str)] = &[("24.05", "nixos-unstable")];
+/// Environment variable overriding [LibXinuxConfig::database_urls].
+pub const DATABASE_URL_ENV: &str = "LIBXINUX_DATABASE_URL";
+/// Environment variable overriding [LibXinuxConfig::storebatch_urls].
+pub const STOREBATCH_URL_ENV: &str = "LIBXINUX_STOREBATCH_URL";
+
 /// Struct containing locations of system configuration files and some user configuration.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
 pub struct LibXinuxConfig {
@@ -22,14 +33,27 @@ pub struct LibXinuxConfig {
     /// Specifies how many NixOS generations to keep. If set to 0, all generations will be kept.
     /// If not set, the default is 5.
     pub generations: Option<u32>,
+    /// Base URLs of the metadata database service, tried in order until one succeeds.
+    /// The database of a nixpkgs revision is fetched from `{url}/{rev}`.
+    /// If empty, [DEFAULT_DATABASE_URL] is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub database_urls: Vec<String>,
+    /// URLs of the store path lookup service, tried in order until one succeeds.
+    /// If empty, [DEFAULT_STOREBATCH_URL] is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storebatch_urls: Vec<String>,
+    /// Branch to check for updates of each NixOS release, e.g. `"24.11": "nixos-24.11-small"`.
+    /// Merged over [DEFAULT_RELEASE_BRANCHES]. Releases missing from both follow `nixos-{release}`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub release_branches: BTreeMap<String, String>,
 }
 
 impl LibXinuxConfig {
-    pub fn write(&self) -> Result<()> {
-        if !Path::new(&*CONFIGDIR).exists() {
-            fs::create_dir_all(&*CONFIGDIR)?;
+    pub fn write(&self, ctx: &Context) -> Result<()> {
+        if !Path::new(ctx.config_dir()).exists() {
+            fs::create_dir_all(ctx.config_dir())?;
         }
-        let mut file = File::create(&*CONFIG)?;
+        let mut file = File::create(ctx.config_file())?;
         file.write_all(serde_json::to_string_pretty(&self)?.as_bytes())?;
         Ok(())
     }
@@ -38,7 +62,7 @@ impl LibXinuxConfig {
         let path = self
             .systemconfig
             .clone()
-            .context("No system config file found")?;
+            .ok_or_else(|| Error::Config("No system config file found".to_string()))?;
         Ok(fs::read_to_string(path)?)
     }
 
@@ -46,23 +70,35 @@ impl LibXinuxConfig {
         let path = self
             .homeconfig
             .clone()
-            .context("No home config file found")?;
+            .ok_or_else(|| Error::Config("No home config file found".to_string()))?;
         Ok(fs::read_to_string(path)?)
     }
 
     pub fn read_flake_file(&self) -> Result<String> {
-        let path = self.flake.clone().context("No flake file found")?;
+        let path = self
+            .flake
+            .clone()
+            .ok_or_else(|| Error::Config("No flake file found".to_string()))?;
         Ok(fs::read_to_string(path)?)
     }
 
     pub fn get_flake_dir(&self) -> Result<String> {
-        let flake_file = PathBuf::from(self.flake.clone().context("No flake file found")?);
-        if flake_file.is_dir() {
-            Ok(flake_file.to_str().context("No path found")?.to_string())
+        let flake_file = PathBuf::from(
+            self.flake
+                .clone()
+                .ok_or_else(|| Error::Config("No flake file found".to_string()))?,
+        );
+        let flake_dir = if flake_file.is_dir() {
+            flake_file.as_path()
         } else {
-            let flake_dir = flake_file.parent().context("No parent found")?;
-            Ok(flake_dir.to_str().context("No path found")?.to_string())
-        }
+            flake_file
+                .parent()
+                .ok_or_else(|| Error::Config("No parent found".to_string()))?
+        };
+        Ok(flake_dir
+            .to_str()
+            .ok_or_else(|| Error::Config("No path found".to_string()))?
+            .to_string())
     }
 
     pub fn get_generation_count(&self) -> Option<u32> {
@@ -87,12 +123,13 @@ pub enum UserPkgType {
 /// Reads the config file and returns the config struct.
 /// If the config file doesn't exist in both the user (`~/.config/nix-data`) and system (`/etc/nix-data`) config directories,
 /// this function will return an error.
-pub fn get_config() -> Result<LibXinuxConfig> {
+pub fn get_config(ctx: &Context) -> Result<LibXinuxConfig> {
+    let user_config = ctx.config_file();
     // Check if user config exists
-    if Path::new(&*CONFIG).exists() {
+    if Path::new(&user_config).exists() {
         // Read user config
         let config: LibXinuxConfig =
-            serde_json::from_reader(BufReader::new(File::open(&*CONFIG)?))?;
+            serde_json::from_reader(BufReader::new(File::open(&user_config)?))?;
         Ok(config)
     } else if Path::new(SYSCONFIG).exists() {
         // Read system config
@@ -100,16 +137,21 @@ pub fn get_config() -> Result<LibXinuxConfig> {
             serde_json::from_reader(BufReader::new(File::open(SYSCONFIG)?))?;
         Ok(config)
     } else {
-        Err(anyhow!("No config file found"))
+        Err(Error::Config("No config file found".to_string()))
     }
 }
 
 /// Get the use package type
-pub fn get_user_pkg_type() -> UserPkgType {
-    if Path::new(&format!("{}/.nix-profile/manifest.json", &*HOME)).exists()
+///
+/// Without a home directory there is no `nix-env` manifest, so [Profile](UserPkgType::Profile) is assumed.
+pub fn get_user_pkg_type(ctx: &Context) -> UserPkgType {
+    let Ok(home) = ctx.home() else {
+        return UserPkgType::Profile;
+    };
+    if Path::new(&format!("{}/.nix-profile/manifest.json", home)).exists()
         || !Path::new("/nix/var/nix/profiles/per-user/root/channels/nixos").exists()
-        || !Path::new(&format!("{}/.nix-profile/manifest.nix", &*HOME)).exists()
-        || if let Ok(m) = fs::read_to_string(format!("{}/.nix-profile/manifest.nix", &*HOME)) {
+        || !Path::new(&format!("{}/.nix-profile/manifest.nix", home)).exists()
+        || if let Ok(m) = fs::read_to_string(format!("{}/.nix-profile/manifest.nix", home)) {
             m == "[ ]"
         } else {
             false
@@ -120,3 +162,68 @@ pub fn get_user_pkg_type() -> UserPkgType {
         UserPkgType::Env
     }
 }
+
+/// Base URLs of the metadata database service in the order they should be tried.
+///
+/// A comma separated list in `LIBXINUX_DATABASE_URL` takes precedence over
+/// [LibXinuxConfig::database_urls], which falls back to [DEFAULT_DATABASE_URL].
+pub fn database_urls(ctx: &Context) -> Vec<String> {
+    endpoint_urls(DATABASE_URL_ENV, DEFAULT_DATABASE_URL, || {
+        get_config(ctx).map(|x| x.database_urls).unwrap_or_default()
+    })
+}
+
+/// URLs of the store path lookup service in the order they should be tried.
+///
+/// A comma separated list in `LIBXINUX_STOREBATCH_URL` takes precedence over
+/// [LibXinuxConfig::storebatch_urls], which falls back to [DEFAULT_STOREBATCH_URL].
+pub fn storebatch_urls(ctx: &Context) -> Vec<String> {
+    endpoint_urls(STOREBATCH_URL_ENV, DEFAULT_STOREBATCH_URL, || {
+        get_config(ctx)
+            .map(|x| x.storebatch_urls)
+            .unwrap_or_default()
+    })
+}
+
+/// Branch to check for updates of the NixOS release `release` (e.g. `24.11`).
+///
+/// [LibXinuxConfig::release_branches] takes precedence over [DEFAULT_RELEASE_BRANCHES],
+/// and releases missing from both follow `nixos-{release}`.
+pub fn release_branch(ctx: &Context, release: &str) -> String {
+    get_config(ctx)
+        .ok()
+        .and_then(|x| x.release_branches.get(release).cloned())
+        .or_else(|| {
+            DEFAULT_RELEASE_BRANCHES
+                .iter()
+                .find(|(x, _)| *x == release)
+                .map(|(_, branch)| branch.to_string())
+        })
+        .unwrap_or_else(|| format!("nixos-{}", release))
+}
+
+fn endpoint_urls(
+    env: &str,
+    default: &str,
+    configured: impl FnOnce() -> Vec<String>,
+) -> Vec<String> {
+    let from_env = std::env::var(env)
+        .map(|x| {
+            x.split(',')
+                .map(|x| x.trim().trim_end_matches('/').to_string())
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if !from_env.is_empty() {
+        return from_env;
+    }
+    let configured = configured()
+        .into_iter()
+        .map(|x| x.trim_end_matches('/').to_string())
+        .collect::<Vec<_>>();
+    if !configured.is_empty() {
+        return configured;
+    }
+    vec![default.to_string()]
+}
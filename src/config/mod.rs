@@ -2,3 +2,5 @@
 /// contains the locations of system configuration
 /// files and some user configuration.
 pub mod configfile;
+/// Previews of changes to the NixOS and home-manager configuration files.
+pub mod plan;
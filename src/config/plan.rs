@@ -0,0 +1,48 @@
+use similar::TextDiff;
+
+/// Changes an install or remove would make to a Nix configuration file.
+///
+/// A plan is computed without touching the system, so it can be shown to the user
+/// before being applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// Path of the configuration file that would be replaced.
+    pub path: String,
+    /// Current contents of the configuration file.
+    pub old_config: String,
+    /// Edited contents of the configuration file.
+    pub new_config: String,
+    /// Unified diff from the current to the edited configuration.
+    pub diff: String,
+    /// Attributes of the packages that would be added.
+    pub added: Vec<String>,
+    /// Attributes of the packages that would be removed.
+    pub removed: Vec<String>,
+    /// Flake to switch to, passed to the helper as `--flake`.
+    pub flake: Option<String>,
+}
+
+impl Plan {
+    pub(crate) fn new(
+        path: String,
+        old_config: String,
+        new_config: String,
+        added: Vec<String>,
+        removed: Vec<String>,
+        flake: Option<String>,
+    ) -> Self {
+        let diff = TextDiff::from_lines(&old_config, &new_config)
+            .unified_diff()
+            .header(&path, &path)
+            .to_string();
+        Self {
+            path,
+            old_config,
+            new_config,
+            diff,
+            added,
+            removed,
+            flake,
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! # Context
+//!
+//! Everything libxinux needs to know about the environment it runs in: the
+//! [CommandRunner] used for external programs, the [RevisionSource] resolving nixpkgs
+//! branches, the user's home directory, where to keep caches and configuration, the Nix
+//! system type and whether the host is NixOS.
+//!
+//! Every value is detected from the environment by default and can be overridden
+//! with a [ContextBuilder], so that system services without a `$HOME` and tests
+//! without a Nix installation can use the library.
+//!
+//! ```
+//! use libxinux::{command::FakeRunner, Context};
+//! use std::sync::Arc;
+//!
+//! let ctx = Context::builder()
+//!     .runner(Arc::new(FakeRunner::new()))
+//!     .home("/home/test")
+//!     .arch("x86_64-linux")
+//!     .is_nixos(true)
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(ctx.cache_dir(), "/home/test/.cache/libxinux");
+//! ```
+
+use crate::{
+    command::{CommandRunner, SystemRunner},
+    get_eval_arch, get_nix_arch, get_nixos_arch,
+    metadata::revision::source::{default_source, RevisionSource},
+    Error, Result,
+};
+use std::{fmt, path::Path, sync::Arc};
+
+/// Environment libxinux operates in. See the [module documentation](self).
+#[derive(Clone)]
+pub struct Context {
+    runner: Arc<dyn CommandRunner>,
+    revision_source: Arc<dyn RevisionSource>,
+    home: Option<String>,
+    cache_dir: String,
+    config_dir: String,
+    arch: String,
+    is_nixos: bool,
+}
+
+impl Context {
+    /// Context of the current user, with every value detected from the environment.
+    pub fn new() -> Result<Self> {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
+    pub fn runner(&self) -> &dyn CommandRunner {
+        &*self.runner
+    }
+
+    pub fn revision_source(&self) -> &dyn RevisionSource {
+        &*self.revision_source
+    }
+
+    /// Home directory of the user. Fails if it was neither set nor found in `$HOME`.
+    pub fn home(&self) -> Result<&str> {
+        self.home
+            .as_deref()
+            .ok_or_else(|| Error::Config("Home directory is not known".to_string()))
+    }
+
+    /// Directory for downloaded databases and other cached data.
+    /// Defaults to `~/.cache/libxinux`.
+    pub fn cache_dir(&self) -> &str {
+        &self.cache_dir
+    }
+
+    /// Directory of the user configuration file. Defaults to `~/.config/libxinux`.
+    pub fn config_dir(&self) -> &str {
+        &self.config_dir
+    }
+
+    /// Path of the user configuration file.
+    pub fn config_file(&self) -> String {
+        format!("{}/config.json", self.config_dir)
+    }
+
+    /// Nix system type, e.g. `x86_64-linux`.
+    pub fn arch(&self) -> &str {
+        &self.arch
+    }
+
+    pub fn is_nixos(&self) -> bool {
+        self.is_nixos
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("home", &self.home)
+            .field("cache_dir", &self.cache_dir)
+            .field("config_dir", &self.config_dir)
+            .field("arch", &self.arch)
+            .field("is_nixos", &self.is_nixos)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Builder for [Context]. Values that are not set are detected in [build](ContextBuilder::build).
+#[derive(Default)]
+pub struct ContextBuilder {
+    runner: Option<Arc<dyn CommandRunner>>,
+    revision_source: Option<Arc<dyn RevisionSource>>,
+    home: Option<String>,
+    cache_dir: Option<String>,
+    config_dir: Option<String>,
+    arch: Option<String>,
+    is_nixos: Option<bool>,
+}
+
+impl ContextBuilder {
+    /// Runner for external programs. Defaults to [SystemRunner].
+    pub fn runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Source resolving nixpkgs branches to revisions. Defaults to
+    /// [default_source] caching in the cache directory.
+    pub fn revision_source(mut self, revision_source: Arc<dyn RevisionSource>) -> Self {
+        self.revision_source = Some(revision_source);
+        self
+    }
+
+    /// Home directory. Defaults to `$HOME`.
+    pub fn home(mut self, home: impl Into<String>) -> Self {
+        self.home = Some(home.into());
+        self
+    }
+
+    pub fn cache_dir(mut self, cache_dir: impl Into<String>) -> Self {
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    pub fn config_dir(mut self, config_dir: impl Into<String>) -> Self {
+        self.config_dir = Some(config_dir.into());
+        self
+    }
+
+    /// Nix system type. Detected from the running system or Nix by default.
+    pub fn arch(mut self, arch: impl Into<String>) -> Self {
+        self.arch = Some(arch.into());
+        self
+    }
+
+    /// Whether the host runs NixOS. Defaults to the presence of `/etc/NIXOS`.
+    pub fn is_nixos(mut self, is_nixos: bool) -> Self {
+        self.is_nixos = Some(is_nixos);
+        self
+    }
+
+    /// Detect the remaining values and create the [Context].
+    ///
+    /// Fails if the cache or config directory is not set and there is no home directory,
+    /// or if the system type is not set and cannot be determined.
+    pub fn build(self) -> Result<Context> {
+        let runner = self.runner.unwrap_or_else(|| Arc::new(SystemRunner));
+        let home = self.home.or_else(|| std::env::var("HOME").ok());
+        let in_home = |dir: &str| {
+            home.as_ref()
+                .map(|home| format!("{}/{}/libxinux", home, dir))
+                .ok_or_else(|| {
+                    Error::Config(format!(
+                        "Home directory is not known, cannot default to ~/{}/libxinux",
+                        dir
+                    ))
+                })
+        };
+        let cache_dir = match self.cache_dir {
+            Some(dir) => dir,
+            None => in_home(".cache")?,
+        };
+        let config_dir = match self.config_dir {
+            Some(dir) => dir,
+            None => in_home(".config")?,
+        };
+        let revision_source = self
+            .revision_source
+            .unwrap_or_else(|| Arc::new(default_source(&cache_dir)));
+        let arch = match self.arch {
+            Some(arch) => arch,
+            None => detect_arch(&*runner)?,
+        };
+        let is_nixos = self
+            .is_nixos
+            .unwrap_or_else(|| Path::new("/etc/NIXOS").exists());
+        Ok(Context {
+            runner,
+            revision_source,
+            home,
+            cache_dir,
+            config_dir,
+            arch,
+            is_nixos,
+        })
+    }
+}
+
+fn detect_arch(runner: &dyn CommandRunner) -> Result<String> {
+    get_nixos_arch()
+        .or_else(|_| get_nix_arch(runner))
+        .or_else(|_| get_eval_arch(runner))
+        .map_err(|_| Error::Config("Could not determine architecture".to_string()))
+}
@@ -0,0 +1,112 @@
+use crate::{command::CommandOutput, nixos::AuthMethod};
+
+/// Result type returned by every libxinux function.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by libxinux.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The libxinux configuration or one of the files it points to is missing or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// The metadata database could not be fetched or opened.
+    #[error("{0}")]
+    Database(String),
+    /// The metadata database uses a layout this version of libxinux cannot read.
+    #[error("Incompatible metadata database (schema version {version}): {reason}")]
+    IncompatibleDatabase { version: u32, reason: String },
+    /// The nixpkgs revision could not be determined.
+    #[error("{0}")]
+    Revision(String),
+    /// An external command exited unsuccessfully.
+    #[error("{command} failed with exit code {}: {stderr}", .code.map_or("unknown".to_string(), |c| c.to_string()))]
+    CommandFailed {
+        command: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    /// The operation was cancelled through its cancellation token.
+    #[error("Operation was cancelled")]
+    Cancelled,
+    /// The user dismissed or failed the authentication prompt.
+    #[error("Authentication was cancelled")]
+    AuthCancelled,
+    /// The requested operation would not change anything.
+    #[error("{0}")]
+    NothingToDo(String),
+    /// A package could not be found.
+    #[error("Package not found: {0}")]
+    PackageNotFound(String),
+    /// A store path did not have the expected `/nix/store/<hash>-<name>` form.
+    #[error("Invalid store path: {0}")]
+    InvalidStorePath(String),
+    /// A flake reference or installable could not be parsed.
+    #[error("Invalid flake reference: {0}")]
+    InvalidFlakeRef(String),
+    #[error("Failed to edit nix file: {0}")]
+    NixEditor(String),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error(transparent)]
+    Search(#[from] tantivy::TantivyError),
+}
+
+impl From<nix_editor::read::ReadError> for Error {
+    fn from(err: nix_editor::read::ReadError) -> Self {
+        Error::NixEditor(err.to_string())
+    }
+}
+
+impl From<nix_editor::write::WriteError> for Error {
+    fn from(err: nix_editor::write::WriteError) -> Self {
+        Error::NixEditor(err.to_string())
+    }
+}
+
+impl Error {
+    /// Turn the output of `command` into [CommandFailed](Error::CommandFailed) if it did not succeed.
+    pub(crate) fn check_output(command: &str, output: &CommandOutput) -> Result<()> {
+        if output.success() {
+            Ok(())
+        } else {
+            Err(Error::CommandFailed {
+                command: command.to_string(),
+                code: output.code,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            })
+        }
+    }
+
+    /// Like [check_output](Error::check_output), but recognizes a cancelled
+    /// `pkexec` or `sudo` prompt as [AuthCancelled](Error::AuthCancelled).
+    pub(crate) fn check_auth_output(auth_method: AuthMethod, output: &CommandOutput) -> Result<()> {
+        if output.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let cancelled = match auth_method {
+            // pkexec exits with 126 when the dialog is dismissed. 127 also covers other
+            // failures, such as a missing helper or a policy denying it, so it is not a
+            // cancellation
+            AuthMethod::Pkexec => output.code == Some(126),
+            AuthMethod::Sudo => {
+                stderr.contains("incorrect password attempt")
+                    || stderr.contains("a password is required")
+            }
+            AuthMethod::Custom(_) => false,
+        };
+        if cancelled {
+            Err(Error::AuthCancelled)
+        } else {
+            Error::check_output(crate::HELPER_EXEC, output)
+        }
+    }
+}
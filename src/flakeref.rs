@@ -0,0 +1,339 @@
+//! # Flake references
+//!
+//! Parsing of flake references as accepted by `nix profile install` and friends,
+//! e.g. `github:NixOS/nixpkgs/nixos-24.05`, `git+https://example.org/repo?ref=main`,
+//! `path:./dir` or the registry name `nixpkgs`.
+//!
+//! Parsed references print back exactly as they were written, so they can be shown
+//! to the user or passed on to Nix unchanged.
+//!
+//! ```
+//! use libxinux::{flakeref::OutputsSpec, PackageAttr};
+//!
+//! let attr: PackageAttr = "github:NixOS/nixpkgs/nixos-24.05?dir=lib#hello^out,dev"
+//!     .parse()
+//!     .unwrap();
+//! let PackageAttr::External { flake, attr: path, outputs } = &attr else {
+//!     unreachable!();
+//! };
+//! assert_eq!(flake.git_ref(), Some("nixos-24.05"));
+//! assert_eq!(flake.dir(), Some("lib"));
+//! assert_eq!(path, "hello");
+//! assert_eq!(
+//!     outputs,
+//!     &Some(OutputsSpec::Names(vec!["out".to_string(), "dev".to_string()]))
+//! );
+//! assert_eq!(
+//!     attr.to_string(),
+//!     "github:NixOS/nixpkgs/nixos-24.05?dir=lib#hello^out,dev"
+//! );
+//! ```
+
+use crate::{Error, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// Where a flake is fetched from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlakeSource {
+    /// `github:owner/repo[/ref-or-rev]`
+    GitHub {
+        owner: String,
+        repo: String,
+        reference: Option<String>,
+    },
+    /// `gitlab:owner/repo[/ref-or-rev]`
+    GitLab {
+        owner: String,
+        repo: String,
+        reference: Option<String>,
+    },
+    /// `git+https://...`, `git+ssh://...` or `git+file://...`, with `url` lacking the `git+` prefix.
+    Git { url: String },
+    /// `path:/some/dir`, or a bare path starting with `.` or `/` if not `explicit`.
+    Path { path: String, explicit: bool },
+    /// `tarball+https://...`, or a plain `http(s)://` URL if not `explicit`.
+    Tarball { url: String, explicit: bool },
+    /// `flake:nixpkgs[/ref[/rev]]`, or a bare registry name like `nixpkgs` if not `explicit`.
+    Indirect {
+        id: String,
+        reference: Option<String>,
+        rev: Option<String>,
+        explicit: bool,
+    },
+    /// Any other scheme (`sourcehut:`, `hg+https:`, `file+https:`, ...), kept verbatim.
+    Other(String),
+}
+
+/// A parsed flake reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlakeRef {
+    pub source: FlakeSource,
+    /// Query parameters such as `rev`, `ref` or `dir`, in the order they were written.
+    pub params: Vec<(String, String)>,
+}
+
+impl FlakeRef {
+    /// Value of the query parameter `name`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Commit hash the reference is locked to, from `?rev=` or the path.
+    pub fn rev(&self) -> Option<&str> {
+        if let Some(rev) = self.param("rev") {
+            return Some(rev);
+        }
+        match &self.source {
+            FlakeSource::GitHub { reference, .. } | FlakeSource::GitLab { reference, .. } => {
+                reference.as_deref().filter(|x| is_rev(x))
+            }
+            FlakeSource::Indirect { reference, rev, .. } => rev
+                .as_deref()
+                .or_else(|| reference.as_deref().filter(|x| is_rev(x))),
+            _ => None,
+        }
+    }
+
+    /// Branch or tag the reference points to, from `?ref=` or the path.
+    pub fn git_ref(&self) -> Option<&str> {
+        if let Some(reference) = self.param("ref") {
+            return Some(reference);
+        }
+        match &self.source {
+            FlakeSource::GitHub { reference, .. }
+            | FlakeSource::GitLab { reference, .. }
+            | FlakeSource::Indirect { reference, .. } => {
+                reference.as_deref().filter(|x| !is_rev(x))
+            }
+            _ => None,
+        }
+    }
+
+    /// Subdirectory containing `flake.nix`, from `?dir=`.
+    pub fn dir(&self) -> Option<&str> {
+        self.param("dir")
+    }
+}
+
+/// Whether `s` looks like a full git commit hash.
+fn is_rev(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn owner_repo(scheme: &str, rest: &str) -> Result<(String, String, Option<String>)> {
+    let mut parts = rest.splitn(3, '/');
+    match (parts.next(), parts.next()) {
+        (Some(owner), Some(repo)) if !owner.is_empty() && !repo.is_empty() => Ok((
+            owner.to_string(),
+            repo.to_string(),
+            parts.next().map(str::to_string),
+        )),
+        _ => Err(Error::InvalidFlakeRef(format!(
+            "{}:{} is missing an owner or repository",
+            scheme, rest
+        ))),
+    }
+}
+
+fn is_registry_id(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl FromStr for FlakeRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(Error::InvalidFlakeRef("empty flake reference".to_string()));
+        }
+        let (base, query) = match s.split_once('?') {
+            Some((base, query)) => (base, Some(query)),
+            None => (s, None),
+        };
+        let params = query
+            .into_iter()
+            .flat_map(|x| x.split('&'))
+            .filter(|x| !x.is_empty())
+            .map(|x| match x.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => (x.to_string(), String::new()),
+            })
+            .collect();
+
+        let source = if let Some(rest) = base.strip_prefix("github:") {
+            let (owner, repo, reference) = owner_repo("github", rest)?;
+            FlakeSource::GitHub {
+                owner,
+                repo,
+                reference,
+            }
+        } else if let Some(rest) = base.strip_prefix("gitlab:") {
+            let (owner, repo, reference) = owner_repo("gitlab", rest)?;
+            FlakeSource::GitLab {
+                owner,
+                repo,
+                reference,
+            }
+        } else if let Some(url) = base.strip_prefix("git+") {
+            FlakeSource::Git {
+                url: url.to_string(),
+            }
+        } else if let Some(path) = base.strip_prefix("path:") {
+            FlakeSource::Path {
+                path: path.to_string(),
+                explicit: true,
+            }
+        } else if base.starts_with('.') || base.starts_with('/') {
+            FlakeSource::Path {
+                path: base.to_string(),
+                explicit: false,
+            }
+        } else if let Some(url) = base.strip_prefix("tarball+") {
+            FlakeSource::Tarball {
+                url: url.to_string(),
+                explicit: true,
+            }
+        } else if base.starts_with("http://") || base.starts_with("https://") {
+            FlakeSource::Tarball {
+                url: base.to_string(),
+                explicit: false,
+            }
+        } else if let Some(rest) = base.strip_prefix("flake:") {
+            indirect(rest, true)?
+        } else if base.contains(':') {
+            FlakeSource::Other(base.to_string())
+        } else {
+            indirect(base, false)?
+        };
+        Ok(FlakeRef { source, params })
+    }
+}
+
+fn indirect(s: &str, explicit: bool) -> Result<FlakeSource> {
+    let mut parts = s.splitn(3, '/');
+    let id = parts.next().unwrap_or_default();
+    if !is_registry_id(id) {
+        return Err(Error::InvalidFlakeRef(format!(
+            "{} is not a valid flake registry name",
+            id
+        )));
+    }
+    Ok(FlakeSource::Indirect {
+        id: id.to_string(),
+        reference: parts.next().map(str::to_string),
+        rev: parts.next().map(str::to_string),
+        explicit,
+    })
+}
+
+impl fmt::Display for FlakeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            FlakeSource::GitHub {
+                owner,
+                repo,
+                reference,
+            } => {
+                write!(f, "github:{}/{}", owner, repo)?;
+                if let Some(reference) = reference {
+                    write!(f, "/{}", reference)?;
+                }
+            }
+            FlakeSource::GitLab {
+                owner,
+                repo,
+                reference,
+            } => {
+                write!(f, "gitlab:{}/{}", owner, repo)?;
+                if let Some(reference) = reference {
+                    write!(f, "/{}", reference)?;
+                }
+            }
+            FlakeSource::Git { url } => write!(f, "git+{}", url)?,
+            FlakeSource::Path { path, explicit } => {
+                write!(f, "{}{}", if *explicit { "path:" } else { "" }, path)?
+            }
+            FlakeSource::Tarball { url, explicit } => {
+                write!(f, "{}{}", if *explicit { "tarball+" } else { "" }, url)?
+            }
+            FlakeSource::Indirect {
+                id,
+                reference,
+                rev,
+                explicit,
+            } => {
+                write!(f, "{}{}", if *explicit { "flake:" } else { "" }, id)?;
+                for part in [reference, rev].into_iter().flatten() {
+                    write!(f, "/{}", part)?;
+                }
+            }
+            FlakeSource::Other(url) => write!(f, "{}", url)?,
+        }
+        for (i, (key, value)) in self.params.iter().enumerate() {
+            let sep = if i == 0 { '?' } else { '&' };
+            write!(f, "{}{}={}", sep, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Outputs selected with a `^` suffix, e.g. `nixpkgs#openssl^out,dev`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputsSpec {
+    /// `^*`
+    All,
+    /// `^out,dev`
+    Names(Vec<String>),
+}
+
+impl OutputsSpec {
+    /// Split a trailing outputs selector off an installable.
+    pub(crate) fn split(s: &str) -> (&str, Option<OutputsSpec>) {
+        match s.rsplit_once('^') {
+            Some((rest, "*")) => (rest, Some(OutputsSpec::All)),
+            Some((rest, names))
+                if !names.is_empty()
+                    && names.split(',').all(|x| {
+                        !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    }) =>
+            {
+                (
+                    rest,
+                    Some(OutputsSpec::Names(
+                        names.split(',').map(str::to_string).collect(),
+                    )),
+                )
+            }
+            _ => (s, None),
+        }
+    }
+}
+
+impl fmt::Display for OutputsSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputsSpec::All => write!(f, "*"),
+            OutputsSpec::Names(names) => write!(f, "{}", names.join(",")),
+        }
+    }
+}
+
+impl Serialize for FlakeRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for FlakeRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
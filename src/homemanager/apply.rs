@@ -0,0 +1,48 @@
+use crate::{
+    command::{self, CancellationToken, CommandSpec},
+    config::{configfile, plan::Plan},
+    progress::{self, ProgressSender},
+    Context, Error, Result, HELPER_EXEC,
+};
+use log::debug;
+use std::fs;
+
+/// Write the configuration of `plan` and switch to it.
+///
+/// Fails without changing anything if the configuration file was modified after the
+/// plan was made.
+pub async fn apply(
+    ctx: &Context,
+    plan: &Plan,
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    if fs::read_to_string(&plan.path)? != plan.old_config {
+        return Err(Error::Config(format!(
+            "{} was modified after the changes were planned",
+            plan.path
+        )));
+    }
+
+    let config = configfile::get_config(ctx)?;
+    let cmd = CommandSpec::new(HELPER_EXEC)
+        .arg("config-home")
+        .args(command::helper_watch_args(cancel))
+        .arg("--output")
+        .arg(&plan.path)
+        .args(if let Some(generations) = config.get_generation_count() {
+            vec!["--generations".to_string(), generations.to_string()]
+        } else {
+            vec![]
+        })
+        .arg("--")
+        .arg("switch")
+        .args(match &plan.flake {
+            Some(flake) => vec!["--flake".to_string(), flake.clone()],
+            None => vec![],
+        })
+        .helper_input(Some(plan.new_config.clone()), cancel);
+    let output = progress::run(ctx.runner(), &cmd, progress).await?;
+    debug!("{:?}", output.code);
+    Error::check_output(HELPER_EXEC, &output)
+}
@@ -1,22 +1,27 @@
 use crate::{
+    command::CommandSpec,
     config::configfile::get_config,
+    metadata::query,
     utils::{misc::get_pname_from_storepath, storedb::get_storebatch},
-    Package, PackageAttr, HOME,
+    Context, Package, PackageAttr, Result,
 };
-use anyhow::Result;
 use rayon::prelude::*;
 
 // nix-store --query --references ~/.local/state/home-manager/gcroots/current-home/home-path
-pub async fn list_references() -> Result<Vec<Package>> {
-    let output = std::process::Command::new("nix-store")
-        .arg("--query")
-        .arg("--references")
-        .arg(format!(
-            "{}/.local/state/home-manager/gcroots/current-home/home-path",
-            &*HOME
-        ))
-        .output()?;
-    let stdout = String::from_utf8(output.stdout)?;
+pub async fn list_references(ctx: &Context) -> Result<Vec<Package>> {
+    let stdout = ctx
+        .runner()
+        .run(
+            &CommandSpec::new("nix-store")
+                .arg("--query")
+                .arg("--references")
+                .arg(format!(
+                    "{}/.local/state/home-manager/gcroots/current-home/home-path",
+                    ctx.home()?
+                )),
+        )
+        .await?
+        .stdout_string()?;
     let store_paths = stdout
         .split("\n")
         .filter(|x| !x.is_empty())
@@ -24,19 +29,20 @@ pub async fn list_references() -> Result<Vec<Package>> {
 
     let mut names = store_paths
         .par_iter()
-        .map(|x| x.split('/').last().unwrap_or(x))
+        .map(|x| x.split('/').next_back().unwrap_or(x))
         .collect::<Vec<_>>();
     names.sort();
 
     // TOOD: Add local caching using
 
-    let storebatch = get_storebatch(names).await?;
+    let storebatch = get_storebatch(ctx, names).await?;
     Ok(storebatch
         .packages
         .into_iter()
         .map(|x| Package {
             attr: PackageAttr::NixPkgs {
                 attr: x.attribute.join("."),
+                outputs: None,
             },
             version: x.version.clone(),
             pname: get_pname_from_storepath(x.store.as_str(), x.version).ok(),
@@ -46,8 +52,8 @@ pub async fn list_references() -> Result<Vec<Package>> {
 }
 
 // List all packages in `home.packages`
-pub fn list(db: &rusqlite::Connection) -> Result<Vec<Package>> {
-    let config = get_config()?;
+pub fn list(ctx: &Context, db: &rusqlite::Connection) -> Result<Vec<Package>> {
+    let config = get_config(ctx)?;
 
     let home_config = config.read_home_config_file()?;
     let home_packages = nix_editor::read::getarrvals(&home_config, "home.packages")?;
@@ -57,26 +63,10 @@ pub fn list(db: &rusqlite::Connection) -> Result<Vec<Package>> {
         .map(|x| x.strip_prefix("pkgs.").unwrap_or(x))
         .collect::<Vec<_>>();
 
-    let mut stmt: rusqlite::Statement =
-        db.prepare("SELECT pname, version FROM pkgs WHERE attribute = ?")?;
     let mut packages = Vec::new();
     for pkg in &pkgs {
-        let mut rows = stmt.query([pkg])?;
-        while let Some(row) = rows.next()? {
-            let pname: String = row.get(0)?;
-            let version: String = row.get(1)?;
-            packages.push(Package {
-                attr: PackageAttr::NixPkgs {
-                    attr: pkg.to_string(),
-                },
-                version: if !version.is_empty() {
-                    Some(version)
-                } else {
-                    None
-                },
-                pname: Some(pname),
-                ..Default::default()
-            });
+        if let Some(info) = query::package(db, pkg)? {
+            packages.push(info.to_package());
         }
     }
     Ok(packages)
@@ -1,5 +1,44 @@
+pub mod apply;
 pub mod install;
 pub mod list;
 pub mod rebuild;
 pub mod remove;
 pub mod update;
+
+use crate::{backend::PackageBackend, Context, Package, PackageUpdate, Result};
+use async_trait::async_trait;
+
+/// [PackageBackend] managing `home.packages` in the home-manager configuration.
+pub struct HomeManagerBackend<'a> {
+    ctx: &'a Context,
+    db: rusqlite::Connection,
+}
+
+impl<'a> HomeManagerBackend<'a> {
+    pub fn new(ctx: &'a Context, db: rusqlite::Connection) -> Self {
+        Self { ctx, db }
+    }
+}
+
+#[async_trait(?Send)]
+impl PackageBackend for HomeManagerBackend<'_> {
+    async fn list(&self) -> Result<Vec<Package>> {
+        list::list(self.ctx, &self.db)
+    }
+
+    async fn install(&self, pkgs: &[&str]) -> Result<()> {
+        install::install(self.ctx, pkgs, &self.db, None, None).await
+    }
+
+    async fn remove(&self, pkgs: &[&str]) -> Result<()> {
+        remove::remove(self.ctx, pkgs, &self.db, None, None).await
+    }
+
+    async fn updatable(&self) -> Result<Vec<PackageUpdate>> {
+        update::updatable(self.ctx, &self.db).await
+    }
+
+    async fn update(&self, _pkgs: &[&str]) -> Result<()> {
+        update::update(self.ctx, None, None).await
+    }
+}
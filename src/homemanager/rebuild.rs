@@ -1,11 +1,20 @@
-use crate::{config::configfile::get_config, HELPER_EXEC};
-use anyhow::Result;
+use crate::{
+    command::{self, CancellationToken, CommandSpec},
+    config::configfile::get_config,
+    progress::{self, ProgressSender},
+    Context, Error, Result, HELPER_EXEC,
+};
 use log::debug;
 
-pub async fn rebuild() -> Result<()> {
-    let config = get_config()?;
-    let output = tokio::process::Command::new(HELPER_EXEC)
+pub async fn rebuild(
+    ctx: &Context,
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let config = get_config(ctx)?;
+    let cmd = CommandSpec::new(HELPER_EXEC)
         .arg("rebuild-home")
+        .args(command::helper_watch_args(cancel))
         .args(if let Some(generations) = config.get_generation_count() {
             vec!["--generations".to_string(), generations.to_string()]
         } else {
@@ -18,8 +27,8 @@ pub async fn rebuild() -> Result<()> {
         } else {
             vec![]
         })
-        .status()
-        .await?;
-    debug!("{}", output);
-    Ok(())
+        .helper_input(None, cancel);
+    let output = progress::run(ctx.runner(), &cmd, progress).await?;
+    debug!("{:?}", output.code);
+    Error::check_output(HELPER_EXEC, &output)
 }
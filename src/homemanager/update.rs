@@ -1,17 +1,25 @@
 use crate::{
-    config::configfile::get_config, homemanager::list::list, utils, PackageUpdate, HELPER_EXEC,
+    command::{self, CancellationToken, CommandSpec},
+    config::configfile::get_config,
+    homemanager::list::list,
+    progress::{self, ProgressSender},
+    utils, Context, Error, PackageUpdate, Result, HELPER_EXEC,
 };
-use anyhow::Result;
 use log::debug;
 
-pub async fn updatable(db: &rusqlite::Connection) -> Result<Vec<PackageUpdate>> {
-    utils::misc::updatable(list(db)?).await
+pub async fn updatable(ctx: &Context, db: &rusqlite::Connection) -> Result<Vec<PackageUpdate>> {
+    utils::misc::updatable(ctx, list(ctx, db)?).await
 }
 
-pub async fn update() -> Result<()> {
-    let config = get_config()?;
-    let output = tokio::process::Command::new(HELPER_EXEC)
+pub async fn update(
+    ctx: &Context,
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let config = get_config(ctx)?;
+    let cmd = CommandSpec::new(HELPER_EXEC)
         .arg("update-home")
+        .args(command::helper_watch_args(cancel))
         .args(if let Some(generations) = config.get_generation_count() {
             vec!["--generations".to_string(), generations.to_string()]
         } else {
@@ -29,8 +37,8 @@ pub async fn update() -> Result<()> {
         } else {
             vec![]
         })
-        .status()
-        .await?;
-    debug!("{}", output);
-    Ok(())
+        .helper_input(None, cancel);
+    let output = progress::run(ctx.runner(), &cmd, progress).await?;
+    debug!("{:?}", output.code);
+    Error::check_output(HELPER_EXEC, &output)
 }
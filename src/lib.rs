@@ -21,19 +21,31 @@
 //! data from the Arch Linux package repositories. We used **tokio** and **reqwest** to create async
 //! versions of the apis.
 
-#![allow(clippy::inherent_to_string)]
-
-use anyhow::Result;
-use std::{fs, process::Command};
+use command::{CommandRunner, CommandSpec};
+use flakeref::{FlakeRef, OutputsSpec};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{cmp::Ordering, fmt, fs, str::FromStr};
+use utils::version::NixVersion;
 
+pub mod backend;
+pub mod command;
 pub mod config;
+mod context;
+mod error;
+pub mod flakeref;
 pub mod homemanager;
 pub mod metadata;
 pub mod nixenv;
 pub mod nixos;
 pub mod profile;
+pub mod progress;
+#[cfg(test)]
+mod test_util;
 pub mod utils;
 
+pub use context::{Context, ContextBuilder};
+pub use error::{Error, Result};
+
 #[derive(Debug, Clone, Default)]
 pub struct Package {
     pub attr: PackageAttr,
@@ -48,89 +60,269 @@ pub struct PackageUpdate {
     pub attr: PackageAttr,
     pub new_version: String,
     pub old_version: String,
+    pub kind: UpdateKind,
+}
+
+impl PackageUpdate {
+    /// Create an update from `old_version` to `new_version`, classified by [NixVersion] ordering.
+    pub fn new(attr: PackageAttr, old_version: String, new_version: String) -> Self {
+        let kind = match NixVersion::from(new_version.as_str()).cmp(&old_version.as_str().into()) {
+            Ordering::Greater => UpdateKind::Upgrade,
+            Ordering::Less => UpdateKind::Downgrade,
+            Ordering::Equal => UpdateKind::Rebuild,
+        };
+        Self {
+            attr,
+            new_version,
+            old_version,
+            kind,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+/// How the new version of a [PackageUpdate] relates to the installed one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpdateKind {
+    /// The new version is newer.
+    #[default]
+    Upgrade,
+    /// The new version is older, e.g. after switching to an older channel.
+    Downgrade,
+    /// Both versions compare equal, only the package itself changed.
+    Rebuild,
+}
+
+/// A package as written by the user, either a nixpkgs attribute like `hello`
+/// or an installable like `github:owner/repo#package^out,dev`.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PackageAttr {
-    NixPkgs { attr: String },
-    External { url: String, attr: String },
+    NixPkgs {
+        attr: String,
+        /// Outputs selected with `^`, or `None` for the default outputs.
+        outputs: Option<OutputsSpec>,
+    },
+    External {
+        flake: FlakeRef,
+        /// Attribute path after `#`, empty if none was given.
+        attr: String,
+        /// Outputs selected with `^`, or `None` for the default outputs.
+        outputs: Option<OutputsSpec>,
+    },
 }
 
 impl PackageAttr {
-    pub fn to_string(&self) -> String {
+    /// Installable to pass to `nix profile`, resolving nixpkgs attributes against the
+    /// `nixpkgs` flake registry entry.
+    pub fn to_installable(&self) -> String {
         match self {
-            PackageAttr::NixPkgs { attr } => attr.to_string(),
-            PackageAttr::External { url, attr } => format!("{}#{}", url, attr),
+            PackageAttr::NixPkgs { .. } => format!("nixpkgs#{}", self),
+            PackageAttr::External { .. } => self.to_string(),
         }
     }
 }
 
+impl FromStr for PackageAttr {
+    type Err = Error;
+
+    /// Anything containing `#` or a scheme, or starting with a path, is parsed as an
+    /// installable. Anything else is a nixpkgs attribute, optionally with an outputs selector.
+    fn from_str(s: &str) -> Result<Self> {
+        let (rest, outputs) = OutputsSpec::split(s);
+        if let Some((flake, attr)) = rest.split_once('#') {
+            return Ok(PackageAttr::External {
+                flake: flake.parse()?,
+                attr: attr.to_string(),
+                outputs,
+            });
+        }
+        if rest.contains(':') || rest.starts_with('.') || rest.starts_with('/') {
+            return Ok(PackageAttr::External {
+                flake: rest.parse()?,
+                attr: String::new(),
+                outputs,
+            });
+        }
+        if rest.is_empty() {
+            return Err(Error::InvalidFlakeRef(
+                "empty package attribute".to_string(),
+            ));
+        }
+        Ok(PackageAttr::NixPkgs {
+            attr: rest.to_string(),
+            outputs,
+        })
+    }
+}
+
+impl fmt::Display for PackageAttr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackageAttr::NixPkgs { attr, outputs } => {
+                write!(f, "{}", attr)?;
+                if let Some(outputs) = outputs {
+                    write!(f, "^{}", outputs)?;
+                }
+                Ok(())
+            }
+            PackageAttr::External {
+                flake,
+                attr,
+                outputs,
+            } => {
+                write!(f, "{}", flake)?;
+                if !attr.is_empty() {
+                    write!(f, "#{}", attr)?;
+                }
+                if let Some(outputs) = outputs {
+                    write!(f, "^{}", outputs)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Serialize for PackageAttr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PackageAttr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl Default for PackageAttr {
     fn default() -> Self {
         PackageAttr::NixPkgs {
             attr: String::new(),
+            outputs: None,
         }
     }
 }
 
-pub fn get_nix_arch() -> Result<String> {
-    let output = Command::new("nix")
-        .arg("--experimental-features")
-        .arg("nix-command")
-        .arg("show-config")
-        .output()?;
-    let stdout = String::from_utf8(output.stdout)?;
+pub fn get_nix_arch(runner: &dyn CommandRunner) -> Result<String> {
+    let output = runner.run_blocking(
+        &CommandSpec::new("nix")
+            .arg("--experimental-features")
+            .arg("nix-command")
+            .arg("show-config"),
+    )?;
+    Error::check_output("nix show-config", &output)?;
+    let stdout = output.stdout_string()?;
     let arch = stdout
-        .split("\n")
-        .collect::<Vec<_>>()
-        .iter()
-        .find(|x| x.contains("system ="))
-        .unwrap()
-        .split("=")
-        .collect::<Vec<_>>()[1]
-        .trim()
-        .to_string();
-    Ok(arch.to_string())
+        .lines()
+        .find_map(|x| x.strip_prefix("system ="))
+        .ok_or_else(|| Error::Config("No system found in nix config".to_string()))?;
+    non_empty_arch(arch, "nix config")
 }
 
 pub fn get_nixos_arch() -> Result<String> {
     let output = fs::read_to_string("/run/current-system/system")?;
-    Ok(output)
+    non_empty_arch(&output, "/run/current-system/system")
 }
 
-pub fn get_eval_arch() -> Result<String> {
-    let output = Command::new("nix")
-        .arg("--experimental-features")
-        .arg("nix-command flakes")
-        .arg("eval")
-        .arg("nixpkgs#system")
-        .arg("--raw")
-        .output()?;
-    let stdout = String::from_utf8(output.stdout)?;
-    Ok(stdout.to_string())
+pub fn get_eval_arch(runner: &dyn CommandRunner) -> Result<String> {
+    let output = runner.run_blocking(
+        &CommandSpec::new("nix")
+            .arg("--experimental-features")
+            .arg("nix-command flakes")
+            .arg("eval")
+            .arg("nixpkgs#system")
+            .arg("--raw"),
+    )?;
+    Error::check_output("nix eval nixpkgs#system", &output)?;
+    non_empty_arch(&output.stdout_string()?, "nix eval nixpkgs#system")
 }
 
-fn get_arch() -> String {
-    if let Ok(arch) = get_nixos_arch() {
-        return arch;
+/// `arch` without surrounding whitespace, or an error naming `source` if it is empty.
+fn non_empty_arch(arch: &str, source: &str) -> Result<String> {
+    match arch.trim() {
+        "" => Err(Error::Config(format!("Empty system found in {}", source))),
+        arch => Ok(arch.to_string()),
     }
-    if let Ok(arch) = get_nix_arch() {
-        return arch;
-    }
-    if let Ok(arch) = get_eval_arch() {
-        return arch;
-    }
-    panic!("Could not determine architecture");
 }
 
-lazy_static::lazy_static! {
-    pub static ref NIXARCH: String = get_arch();
-    pub static ref CACHEDIR: String = format!("{}/.cache/libxinux", std::env::var("HOME").unwrap());
-    pub static ref CONFIGDIR: String = format!("{}/.config/libxinux", std::env::var("HOME").unwrap());
-    pub static ref CONFIG: String = format!("{}/config.json", &*CONFIGDIR);
-    pub static ref HOME: String = std::env::var("HOME").unwrap();
-    pub static ref IS_NIXOS: bool = std::path::Path::new("/etc/NIXOS").exists();
-}
 static SYSCONFIG: &str = "/etc/libxinux/config.json";
 static HELPER_EXEC: &str = "libxinux-helper";
 static ICON_UPDATER_EXEC: &str = "update-icons.trigger";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{CommandOutput, FakeRunner};
+
+    fn round_trip(s: &str) -> PackageAttr {
+        let attr: PackageAttr = s.parse().unwrap();
+        assert_eq!(attr.to_string(), s);
+        assert_eq!(
+            serde_json::to_string(&attr).unwrap(),
+            serde_json::to_string(s).unwrap()
+        );
+        attr
+    }
+
+    #[test]
+    fn nixpkgs_attr_with_outputs() {
+        let attr = round_trip("hello^out");
+        assert_eq!(
+            attr,
+            PackageAttr::NixPkgs {
+                attr: "hello".to_string(),
+                outputs: Some(OutputsSpec::Names(vec!["out".to_string()])),
+            }
+        );
+        assert_eq!(attr.to_installable(), "nixpkgs#hello^out");
+    }
+
+    #[test]
+    fn path_installable() {
+        let attr = round_trip("./foo#bar");
+        assert!(matches!(attr, PackageAttr::External { .. }));
+        assert_eq!(attr.to_installable(), "./foo#bar");
+    }
+
+    #[test]
+    fn bare_nixpkgs() {
+        let attr = round_trip("nixpkgs");
+        assert_eq!(attr.to_installable(), "nixpkgs#nixpkgs");
+    }
+
+    #[test]
+    fn nix_arch_from_config() {
+        let runner = FakeRunner::new().on(
+            "nix",
+            &[],
+            CommandOutput::with_stdout("sandbox = true\nsystem = x86_64-linux\n"),
+        );
+        assert_eq!(get_nix_arch(&runner).unwrap(), "x86_64-linux");
+    }
+
+    #[test]
+    fn nix_arch_rejects_failure_and_empty_system() {
+        let runner = FakeRunner::new().on("nix", &[], CommandOutput::with_failure(1, "error"));
+        assert!(matches!(
+            get_nix_arch(&runner),
+            Err(Error::CommandFailed { .. })
+        ));
+        let runner = FakeRunner::new().on("nix", &[], CommandOutput::with_stdout("system =\n"));
+        assert!(matches!(get_nix_arch(&runner), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn eval_arch_rejects_failure_and_empty_output() {
+        let runner = FakeRunner::new().on("nix", &[], CommandOutput::with_stdout("aarch64-linux"));
+        assert_eq!(get_eval_arch(&runner).unwrap(), "aarch64-linux");
+        let runner = FakeRunner::new().on("nix", &[], CommandOutput::with_failure(1, "error"));
+        assert!(matches!(
+            get_eval_arch(&runner),
+            Err(Error::CommandFailed { .. })
+        ));
+        let runner = FakeRunner::new().on("nix", &[], CommandOutput::with_stdout(""));
+        assert!(matches!(get_eval_arch(&runner), Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,280 @@
+//! # Local database builder
+//!
+//! Builds the metadata database that [fetch_database](super::database::fetch_database)
+//! would otherwise download, by evaluating a local nixpkgs checkout or store path.
+//! This makes libxinux usable on machines without access to the database mirrors.
+//!
+//! The database is written to the cache under the revision it describes, so the rest
+//! of the library picks it up as if it had been downloaded. As it could not be
+//! downloaded again, the revision is pinned in the cache.
+
+use super::{cache, database::verify_database, schema::SchemaVersion};
+use crate::{
+    command::{CancellationToken, CommandSpec},
+    utils::misc::get_pname_version,
+    Context, Error, Result,
+};
+use log::debug;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::fs;
+
+/// nixpkgs configuration used when evaluating, so that every package is listed.
+const NIXPKGS_CONFIG: &str = "{ allowAliases = false; allowUnfree = true; allowBroken = true; allowInsecurePredicate = _: true; }";
+
+/// Command used to list the packages of nixpkgs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BuildSource {
+    /// `nix-env -qaP --json --meta`, which includes the full `meta` attribute.
+    #[default]
+    NixEnv,
+    /// `nix search --json`, which only provides names, versions and descriptions.
+    NixSearch,
+}
+
+/// A package as listed by `nix-env` or `nix search`.
+#[derive(Debug, Default, Deserialize)]
+struct ListedPackage {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    pname: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    meta: HashMap<String, Value>,
+}
+
+/// Row of the database built for one package.
+#[derive(Debug, Default)]
+struct Row {
+    attribute: String,
+    pname: String,
+    version: String,
+    description: Option<String>,
+    long_description: Option<String>,
+    broken: bool,
+    insecure: bool,
+    unfree: bool,
+    homepage: Option<String>,
+    license: Option<String>,
+    maintainers: Option<String>,
+    platforms: Option<String>,
+    position: Option<String>,
+    main_program: Option<String>,
+}
+
+/// Build the metadata database for the nixpkgs at `nixpkgs` and store it as revision `rev`.
+///
+/// `nixpkgs` is a path to a nixpkgs checkout or store path. Returns the path of the
+/// database, which replaces any existing database for `rev`. The revision is
+/// [pinned](cache::pin), so cleanups keep the database until it is unpinned.
+pub async fn build_database(
+    ctx: &Context,
+    nixpkgs: &str,
+    rev: &str,
+    source: BuildSource,
+    cancel: Option<&CancellationToken>,
+) -> Result<String> {
+    let cmd = match source {
+        BuildSource::NixEnv => CommandSpec::new("nix-env")
+            .arg("-f")
+            .arg(nixpkgs)
+            .arg("--arg")
+            .arg("config")
+            .arg(NIXPKGS_CONFIG)
+            .arg("--arg")
+            .arg("overlays")
+            .arg("[]")
+            .arg("-qaP")
+            .arg("--json")
+            .arg("--meta"),
+        BuildSource::NixSearch => CommandSpec::new("nix")
+            .arg("--extra-experimental-features")
+            .arg("nix-command flakes")
+            .arg("search")
+            .arg("--json")
+            .arg(format!("path:{}", nixpkgs))
+            .arg("^"),
+    }
+    .cancel_on(cancel);
+    debug!("Building database for {} with {}", rev, cmd);
+    let output = ctx.runner().run(&cmd).await?;
+    Error::check_output(&cmd.program, &output)?;
+    let listed: HashMap<String, ListedPackage> = serde_json::from_slice(&output.stdout)?;
+
+    let prefixes = [
+        format!("legacyPackages.{}.", ctx.arch()),
+        format!("packages.{}.", ctx.arch()),
+    ];
+    // Sorted so that a `legacyPackages` attribute comes before the `packages` one it
+    // shares its name with, and is the one kept
+    let mut listed = listed.into_iter().collect::<Vec<_>>();
+    listed.sort_by(|a, b| a.0.cmp(&b.0));
+    let rows = listed
+        .into_iter()
+        .filter_map(|(attr, pkg)| {
+            let attr = prefixes
+                .iter()
+                .find_map(|x| attr.strip_prefix(x.as_str()))
+                .unwrap_or(&attr)
+                .to_string();
+            row(attr, pkg)
+        })
+        .collect::<Vec<_>>();
+    debug!("Found {} packages", rows.len());
+
+    fs::create_dir_all(ctx.cache_dir()).await?;
+    let outpath = cache::database_path(ctx, rev);
+    let tmppath = format!("{}.{}.tmp", outpath, std::process::id());
+    let _ = fs::remove_file(&tmppath).await;
+    let written = {
+        let tmppath = tmppath.clone();
+        tokio::task::spawn_blocking(move || {
+            write_database(&tmppath, &rows)?;
+            verify_database(&tmppath)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    };
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmppath).await;
+        return Err(err);
+    }
+    if let Err(err) = cache::insert_pinned(ctx, rev, &tmppath) {
+        let _ = fs::remove_file(&tmppath).await;
+        return Err(err);
+    }
+    Ok(outpath)
+}
+
+fn row(attribute: String, pkg: ListedPackage) -> Option<Row> {
+    let (pname, version) = match (pkg.pname, pkg.version) {
+        (Some(pname), Some(version)) => (pname, version),
+        (pname, version) => {
+            let (parsed_pname, parsed_version) = get_pname_version(pkg.name.as_deref()?).ok()?;
+            (
+                pname.unwrap_or(parsed_pname),
+                version.or(parsed_version).unwrap_or_default(),
+            )
+        }
+    };
+    let meta = pkg.meta;
+    let string = |key: &str| meta.get(key).and_then(Value::as_str).map(str::to_string);
+    let flag = |key: &str| meta.get(key).and_then(Value::as_bool).unwrap_or(false);
+    Some(Row {
+        attribute,
+        pname,
+        version,
+        description: string("description").or(pkg.description),
+        long_description: string("longDescription"),
+        broken: flag("broken"),
+        insecure: flag("insecure")
+            || meta
+                .get("knownVulnerabilities")
+                .and_then(Value::as_array)
+                .is_some_and(|x| !x.is_empty()),
+        unfree: flag("unfree"),
+        homepage: match meta.get("homepage") {
+            Some(Value::Array(urls)) => urls.first().and_then(Value::as_str).map(str::to_string),
+            Some(Value::String(url)) => Some(url.clone()),
+            _ => None,
+        },
+        license: names(meta.get("license"), &["spdxId", "shortName", "fullName"]),
+        maintainers: names(meta.get("maintainers"), &["github", "name", "email"]),
+        platforms: names(meta.get("platforms"), &[]),
+        position: string("position"),
+        main_program: string("mainProgram"),
+    })
+}
+
+/// JSON array of the names in `value`, which is a string, an attribute set or a list of
+/// those. Attribute sets are named by the first of `keys` they contain.
+fn names(value: Option<&Value>, keys: &[&str]) -> Option<String> {
+    let name = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Object(obj) => keys
+            .iter()
+            .find_map(|key| obj.get(*key).and_then(Value::as_str))
+            .map(str::to_string),
+        _ => None,
+    };
+    let names = match value? {
+        Value::Array(values) => values.iter().filter_map(name).collect(),
+        value => vec![name(value)?],
+    };
+    serde_json::to_string(&names).ok()
+}
+
+fn write_database(path: &str, rows: &[Row]) -> Result<()> {
+    let mut db = rusqlite::Connection::open(path)?;
+    db.execute_batch(&format!(
+        "PRAGMA user_version = {};
+         CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, pname TEXT, version TEXT);
+         CREATE TABLE meta (attribute TEXT PRIMARY KEY, description TEXT, long_description TEXT,
+             broken INTEGER, insecure INTEGER, unfree INTEGER, homepage TEXT, license TEXT,
+             maintainers TEXT, platforms TEXT, position TEXT, main_program TEXT);
+         CREATE INDEX pkgs_pname ON pkgs (pname);",
+        SchemaVersion::V1.number()
+    ))?;
+    let tx = db.transaction()?;
+    {
+        // Attributes under both `legacyPackages` and `packages` end up with the same
+        // name, the first row is kept
+        let mut pkgs = tx.prepare("INSERT OR IGNORE INTO pkgs VALUES (?, ?, ?)")?;
+        let mut meta =
+            tx.prepare("INSERT OR IGNORE INTO meta VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+        for row in rows {
+            pkgs.execute((&row.attribute, &row.pname, &row.version))?;
+            meta.execute((
+                &row.attribute,
+                &row.description,
+                &row.long_description,
+                row.broken,
+                row.insecure,
+                row.unfree,
+                &row.homepage,
+                &row.license,
+                &row.maintainers,
+                &row.platforms,
+                &row.position,
+                &row.main_program,
+            ))?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{query, schema};
+
+    #[test]
+    fn keeps_first_of_duplicate_attributes() {
+        let dir = std::env::temp_dir().join(format!("libxinux-builder-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dup.db").to_string_lossy().to_string();
+        let row = |version: &str, description: &str| Row {
+            attribute: "hello".to_string(),
+            pname: "hello".to_string(),
+            version: version.to_string(),
+            description: Some(description.to_string()),
+            ..Default::default()
+        };
+
+        write_database(&path, &[row("2.12.1", "first"), row("2.12.2", "second")]).unwrap();
+
+        verify_database(&path).unwrap();
+        let db = schema::open(&path).unwrap();
+        let packages = query::packages_by_pname(&db, "hello").unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].version, "2.12.1");
+        assert_eq!(packages[0].description.as_deref(), Some("first"));
+    }
+}
@@ -0,0 +1,372 @@
+//! # Database cache
+//!
+//! Downloaded metadata databases are kept in the [cache directory](crate::Context::cache_dir)
+//! as `{rev}.db`, with their search index in `{rev}.index`. The index in `cache.json`
+//! records which revisions are in use by the system and by update checks, when each
+//! revision was last used and which ones are pinned.
+//!
+//! Whenever the database of the system or of the latest revision is fetched, databases
+//! that are neither in use nor pinned are removed. [prune] and [clear] give finer control over the size of the cache.
+//!
+//! Every access to the index holds an exclusive lock on `cache.lock`, so several
+//! processes can share a cache directory.
+
+use super::{database::DatabaseCacheEntry, search};
+use crate::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::ErrorKind,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// Contents of `cache.json`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub(crate) struct CacheIndex {
+    /// Revision of the installed system.
+    #[serde(default)]
+    pub current_rev: String,
+    /// Latest revision, used to check for updates.
+    #[serde(default)]
+    pub new_rev: String,
+    #[serde(default)]
+    pub revisions: BTreeMap<String, RevisionEntry>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub(crate) struct RevisionEntry {
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+impl CacheIndex {
+    /// Whether the database of `rev` has to be kept when cleaning up.
+    fn keeps(&self, rev: &str) -> bool {
+        rev == self.current_rev
+            || rev == self.new_rev
+            || self.revisions.get(rev).is_some_and(|x| x.pinned)
+    }
+}
+
+/// A revision whose database is in the cache.
+#[derive(Debug, Clone)]
+pub struct CachedRevision {
+    pub rev: String,
+    /// Size of the files kept for the revision, in bytes.
+    pub size: u64,
+    /// When the database was last opened, or downloaded if that is not recorded.
+    pub last_used: Option<DateTime<Utc>>,
+    pub pinned: bool,
+    /// Whether this is the revision of the installed system.
+    pub current: bool,
+    /// Whether this is the latest revision, used to check for updates.
+    pub new: bool,
+}
+
+/// Limits for [prune]. Revisions exceeding any of them are removed.
+#[derive(Debug, Clone, Default)]
+pub struct PrunePolicy {
+    /// Remove revisions that have not been used for longer than this.
+    pub max_age: Option<Duration>,
+    /// Remove the least recently used revisions until the cache is at most this many bytes.
+    pub max_size: Option<u64>,
+}
+
+/// Exclusive lock on the cache directory, released when dropped.
+pub(crate) struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    pub(crate) fn acquire(ctx: &Context) -> Result<Self> {
+        Self::acquire_file(&format!("{}/cache.lock", ctx.cache_dir()))
+    }
+
+    /// Exclusive lock on the file at `path`, created if needed. Blocks until it is free.
+    pub(crate) fn acquire_file(path: &str) -> Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(CacheLock { _file: file })
+    }
+}
+
+fn index_path(ctx: &Context) -> String {
+    format!("{}/cache.json", ctx.cache_dir())
+}
+
+/// Path of the database of `rev`.
+pub fn database_path(ctx: &Context, rev: &str) -> String {
+    format!("{}/{}.db", ctx.cache_dir(), rev)
+}
+
+/// Read the index. Must be called with the [CacheLock] held.
+pub(crate) fn read_index(ctx: &Context) -> Result<CacheIndex> {
+    match fs::read_to_string(index_path(ctx)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(index) => Ok(index),
+            Err(err) => {
+                warn!("Ignoring invalid cache index: {}", err);
+                Ok(CacheIndex::default())
+            }
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(CacheIndex::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Replace the index. Must be called with the [CacheLock] held.
+pub(crate) fn write_index(ctx: &Context, index: &CacheIndex) -> Result<()> {
+    let path = index_path(ctx);
+    let tmppath = format!("{}.{}.tmp", path, std::process::id());
+    fs::write(&tmppath, serde_json::to_string_pretty(index)?)?;
+    fs::rename(&tmppath, &path)?;
+    Ok(())
+}
+
+/// Lock the cache and update its index with `f`.
+fn update_index<T>(ctx: &Context, f: impl FnOnce(&mut CacheIndex) -> Result<T>) -> Result<T> {
+    let _lock = CacheLock::acquire(ctx)?;
+    let mut index = read_index(ctx)?;
+    let result = f(&mut index)?;
+    write_index(ctx, &index)?;
+    Ok(result)
+}
+
+/// Record that the database of `rev` is used as `entry`.
+pub(crate) fn record_use(ctx: &Context, rev: &str, entry: &DatabaseCacheEntry) -> Result<()> {
+    update_index(ctx, |index| {
+        match entry {
+            DatabaseCacheEntry::Current => index.current_rev = rev.to_string(),
+            DatabaseCacheEntry::New => index.new_rev = rev.to_string(),
+            DatabaseCacheEntry::Other => {}
+        }
+        index
+            .revisions
+            .entry(rev.to_string())
+            .or_default()
+            .last_used = Some(Utc::now());
+        Ok(())
+    })
+}
+
+/// Move the database at `path` into the cache as the database of `rev` and pin it, so
+/// that it is kept even though it cannot be downloaded again.
+pub(crate) fn insert_pinned(ctx: &Context, rev: &str, path: &str) -> Result<()> {
+    update_index(ctx, |index| {
+        fs::rename(path, database_path(ctx, rev))?;
+        let entry = index.revisions.entry(rev.to_string()).or_default();
+        entry.pinned = true;
+        entry.last_used = Some(Utc::now());
+        Ok(())
+    })
+}
+
+/// Record that the database of `rev` was just used.
+pub(crate) fn touch(ctx: &Context, rev: &str) -> Result<()> {
+    update_index(ctx, |index| {
+        index
+            .revisions
+            .entry(rev.to_string())
+            .or_default()
+            .last_used = Some(Utc::now());
+        Ok(())
+    })
+}
+
+/// Remove every database that is not in use or pinned, except the one of `rev`.
+pub(crate) fn cleanup(ctx: &Context, rev: &str) -> Result<()> {
+    update_index(ctx, |index| {
+        for cached in cached_revs(ctx)? {
+            if cached != rev && !index.keeps(&cached) {
+                debug!("Removing cached database {}", cached);
+                remove_files(ctx, &cached)?;
+                index.revisions.remove(&cached);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Revisions with a database in the cache.
+fn cached_revs(ctx: &Context) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(ctx.cache_dir()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut revs = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().unwrap_or_default() == "db" {
+            if let Some(stem) = path.file_stem() {
+                revs.push(stem.to_string_lossy().to_string());
+            }
+        }
+    }
+    revs.sort();
+    Ok(revs)
+}
+
+/// Files kept for `rev`: the database and its search index.
+fn files(ctx: &Context, rev: &str) -> Vec<PathBuf> {
+    let database = database_path(ctx, rev);
+    let mut files = vec![];
+    files.extend(search::index_path(&database));
+    files.push(PathBuf::from(database));
+    files
+}
+
+fn remove_files(ctx: &Context, rev: &str) -> Result<()> {
+    for path in files(ctx, rev) {
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match removed {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn size(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|x| x.ok())
+                    .map(|x| size(&x.path()))
+                    .sum()
+            })
+            .unwrap_or(0),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
+
+fn describe(ctx: &Context, index: &CacheIndex, rev: String) -> CachedRevision {
+    let entry = index.revisions.get(&rev).cloned().unwrap_or_default();
+    let last_used = entry.last_used.or_else(|| {
+        fs::metadata(database_path(ctx, &rev))
+            .and_then(|x| x.modified())
+            .ok()
+            .map(DateTime::<Utc>::from)
+    });
+    CachedRevision {
+        size: files(ctx, &rev).iter().map(|x| size(x)).sum(),
+        last_used,
+        pinned: entry.pinned,
+        current: rev == index.current_rev,
+        new: rev == index.new_rev,
+        rev,
+    }
+}
+
+/// Revisions in the cache, most recently used first.
+pub fn list(ctx: &Context) -> Result<Vec<CachedRevision>> {
+    let _lock = CacheLock::acquire(ctx)?;
+    let index = read_index(ctx)?;
+    let mut revisions = cached_revs(ctx)?
+        .into_iter()
+        .map(|rev| describe(ctx, &index, rev))
+        .collect::<Vec<_>>();
+    revisions.sort_by_key(|x| std::cmp::Reverse(x.last_used));
+    Ok(revisions)
+}
+
+/// Keep the database of `rev` until it is [unpinned](unpin), even if it is not in use.
+///
+/// The database does not have to be cached yet.
+pub fn pin(ctx: &Context, rev: &str) -> Result<()> {
+    update_index(ctx, |index| {
+        index.revisions.entry(rev.to_string()).or_default().pinned = true;
+        Ok(())
+    })
+}
+
+pub fn unpin(ctx: &Context, rev: &str) -> Result<()> {
+    update_index(ctx, |index| {
+        if let Some(entry) = index.revisions.get_mut(rev) {
+            entry.pinned = false;
+        }
+        Ok(())
+    })
+}
+
+/// Remove revisions exceeding the limits of `policy`, least recently used first.
+///
+/// Pinned revisions and the revision of the installed system are never removed.
+/// Returns the removed revisions.
+pub fn prune(ctx: &Context, policy: &PrunePolicy) -> Result<Vec<String>> {
+    update_index(ctx, |index| {
+        let mut revisions = cached_revs(ctx)?
+            .into_iter()
+            .map(|rev| describe(ctx, index, rev))
+            .collect::<Vec<_>>();
+        // Oldest first
+        revisions.sort_by_key(|x| x.last_used);
+        let mut total = revisions.iter().map(|x| x.size).sum::<u64>();
+        let now = Utc::now();
+        let mut removed = vec![];
+        for revision in revisions {
+            if revision.pinned || revision.current {
+                continue;
+            }
+            let expired = policy
+                .max_age
+                .is_some_and(|max_age| revision.last_used.is_none_or(|used| now - used > max_age));
+            let oversized = policy.max_size.is_some_and(|max_size| total > max_size);
+            if expired || oversized {
+                debug!("Pruning cached database {}", revision.rev);
+                remove_files(ctx, &revision.rev)?;
+                index.revisions.remove(&revision.rev);
+                if index.new_rev == revision.rev {
+                    index.new_rev.clear();
+                }
+                total -= revision.size;
+                removed.push(revision.rev);
+            }
+        }
+        Ok(removed)
+    })
+}
+
+/// Remove every cached database, including pinned ones, and reset the index.
+pub fn clear(ctx: &Context) -> Result<()> {
+    let _lock = CacheLock::acquire(ctx)?;
+    for rev in cached_revs(ctx)? {
+        remove_files(ctx, &rev)?;
+    }
+    // Partial downloads, unpacked databases and search indexes left behind by
+    // interrupted runs
+    for entry in fs::read_dir(ctx.cache_dir())? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.contains(".index.") && name.ends_with(".tmp") {
+            fs::remove_dir_all(&path)?;
+        } else if name.ends_with(".br.part")
+            || name.ends_with(".br.part.json")
+            || (name.contains(".db.") && name.ends_with(".tmp"))
+        {
+            fs::remove_file(&path)?;
+        }
+    }
+    write_index(ctx, &CacheIndex::default())
+}
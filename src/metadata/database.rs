@@ -1,107 +1,592 @@
-use std::path::PathBuf;
+use std::{
+    io::{Read, Seek, Write},
+    path::PathBuf,
+};
 
-use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use reqwest::{
+    header::{ACCEPT_ENCODING, ETAG, IF_RANGE, RANGE},
+    StatusCode,
+};
 use serde::{Deserialize, Serialize};
-use tokio::fs;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
 
-use super::revision::get_revision;
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct DatabaseCache {
-    current_rev: String,
-    new_rev: String,
-}
+use super::{
+    cache,
+    revision::{get_local_revision, get_revision},
+    schema,
+};
+use crate::{config::configfile::database_urls, Context, Error, Result};
 
+/// What a fetched database is used for, which decides how long it stays in the cache.
 pub enum DatabaseCacheEntry {
+    /// The revision of the installed system.
     Current,
+    /// The latest revision, used to check for updates.
     New,
+    /// Any other revision. It replaces neither of the above and is kept until the next
+    /// cleanup.
+    Other,
 }
 
-pub async fn fetch_database(rev: &str, entry: DatabaseCacheEntry) -> Result<String> {
-    let cache_file_path = format!("{}/.cache/libxinux/cache.json", std::env::var("HOME")?);
-    if !PathBuf::from(&cache_file_path).exists() {
-        fs::create_dir_all(
-            PathBuf::from(&cache_file_path)
-                .parent()
-                .context("Invalid path")?,
-        )
-        .await?;
-        fs::write(&cache_file_path, r#"{"current_rev": "", "new_rev": ""}"#).await?;
-    }
-    let cache_content = fs::read_to_string(&cache_file_path).await?;
-    let mut cachejson: DatabaseCache = serde_json::from_str(&cache_content)?;
-    match entry {
-        DatabaseCacheEntry::Current if rev != cachejson.current_rev => {
-            cachejson.current_rev = rev.to_string();
-        }
-        DatabaseCacheEntry::New if rev != cachejson.new_rev => {
-            cachejson.new_rev = rev.to_string();
-        }
-        _ => {}
+impl DatabaseCacheEntry {
+    /// Whether fetching the entry makes the databases it replaces obsolete.
+    fn replaces(&self) -> bool {
+        !matches!(self, DatabaseCacheEntry::Other)
     }
-    fs::write(&cache_file_path, serde_json::to_string(&cachejson)?).await?;
+}
+
+/// Called with the number of bytes downloaded so far and the total size, if known.
+pub type DownloadProgress<'a> = &'a mut dyn FnMut(u64, Option<u64>);
 
-    let outpath = format!("{}/.cache/libxinux/{}.db", std::env::var("HOME")?, rev);
+/// Path of the database for `rev`, downloading it into the cache if needed.
+///
+/// `on_progress` is called while the database is downloaded.
+pub async fn fetch_database(
+    ctx: &Context,
+    rev: &str,
+    entry: DatabaseCacheEntry,
+    mut on_progress: Option<DownloadProgress<'_>>,
+) -> Result<String> {
+    cache::record_use(ctx, rev, &entry)?;
+
+    let outpath = cache::database_path(ctx, rev);
+
+    // Held until the database is in place, so that processes fetching the same
+    // revision do not write to the same partial download
+    let _lock = {
+        let lockpath = format!("{}.lock", outpath);
+        tokio::task::spawn_blocking(move || cache::CacheLock::acquire_file(&lockpath))
+            .await
+            .map_err(std::io::Error::other)??
+    };
 
     if PathBuf::from(&outpath).exists() {
-        cleanup(&outpath, &cachejson).await?;
-        return Ok(outpath);
+        match check_database(&outpath) {
+            Ok(()) => {
+                if entry.replaces() {
+                    cache::cleanup(ctx, rev)?;
+                }
+                return Ok(outpath);
+            }
+            Err(err) if is_corrupt(&err) => {
+                warn!("Removing corrupt database {}: {}", outpath, err);
+                fs::remove_file(&outpath).await?;
+            }
+            Err(err) => return Err(err),
+        }
     }
 
-    let client = reqwest::Client::builder().brotli(true).build()?;
-    let output = client
-        .get(format!("https://api.snowflakeos.org/libsnow/{}", rev))
-        .send()
-        .await?;
+    fs::create_dir_all(
+        PathBuf::from(&outpath)
+            .parent()
+            .ok_or_else(|| Error::Database("Invalid path".to_string()))?,
+    )
+    .await?;
+
+    // The compressed download is kept until it has been unpacked, so an interrupted
+    // download resumes where it stopped. The database is unpacked next to the final
+    // location and only moved into place once verified.
+    let partial = format!("{}.br.part", outpath);
+    let tmppath = format!("{}.{}.tmp", outpath, std::process::id());
+    let client = reqwest::Client::builder().brotli(false).build()?;
+    let mut last_err = None;
+    for url in database_urls(ctx) {
+        if let Err(err) = download_compressed(&client, &url, rev, &partial, &mut on_progress).await
+        {
+            warn!("Request to {} failed: {}", url, err);
+            last_err = Some(err);
+            continue;
+        }
+        match unpack_database(&client, &url, rev, &partial, &tmppath).await {
+            Ok(()) => {
+                fs::rename(&tmppath, &outpath).await?;
+                remove_partial(&partial).await;
+                if entry.replaces() {
+                    cache::cleanup(ctx, rev)?;
+                }
+                return Ok(outpath);
+            }
+            Err(err) => {
+                warn!("Discarding database downloaded from {}: {}", url, err);
+                let _ = fs::remove_file(&tmppath).await;
+                remove_partial(&partial).await;
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| Error::Config("No URLs configured".to_string())))
+}
+
+/// Where a partial download came from, stored next to it as `{partial}.json`.
+#[derive(Debug, Deserialize, Serialize)]
+struct PartialSource {
+    url: String,
+    etag: Option<String>,
+}
+
+fn partial_source_path(partial: &str) -> String {
+    format!("{}.json", partial)
+}
+
+async fn remove_partial(partial: &str) {
+    let _ = fs::remove_file(partial).await;
+    let _ = fs::remove_file(partial_source_path(partial)).await;
+}
 
+/// Download the brotli compressed database for `rev` from the mirror at `url` to `path`,
+/// continuing a previous partial download with a range request.
+///
+/// Mirrors do not necessarily serve identical files, so a partial download is only
+/// continued from the mirror it came from, or from one serving the same ETag.
+async fn download_compressed(
+    client: &reqwest::Client,
+    url: &str,
+    rev: &str,
+    path: &str,
+    on_progress: &mut Option<DownloadProgress<'_>>,
+) -> Result<()> {
+    let source = fs::read(partial_source_path(path))
+        .await
+        .ok()
+        .and_then(|x| serde_json::from_slice::<PartialSource>(&x).ok());
+    let mut offset = match &source {
+        Some(source) if source.url == url || source.etag.is_some() => {
+            fs::metadata(path).await.map(|x| x.len()).unwrap_or(0)
+        }
+        _ => 0,
+    };
+    let mut request = client
+        .get(format!("{}/{}", url, rev))
+        .header(ACCEPT_ENCODING, "br");
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+        // The server sends everything if its file does not have this ETag
+        if let Some(etag) = source.as_ref().and_then(|x| x.etag.as_ref()) {
+            request = request.header(IF_RANGE, etag);
+        }
+    }
+    let mut output = request.send().await?;
     let status = output.status();
+    if status == StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+        // A previous attempt already received everything
+        return Ok(());
+    }
     if !status.is_success() {
-        return Err(anyhow!("Failed to fetch database: {}", status));
+        return Err(Error::Database(format!(
+            "Failed to fetch database: {}",
+            status
+        )));
     }
+    let mut file = if status == StatusCode::PARTIAL_CONTENT && offset > 0 {
+        fs::OpenOptions::new().append(true).open(path).await?
+    } else {
+        // A new download, or the server ignored the range and sent everything
+        offset = 0;
+        let source = PartialSource {
+            url: url.to_string(),
+            etag: output
+                .headers()
+                .get(ETAG)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string),
+        };
+        fs::write(partial_source_path(path), serde_json::to_vec(&source)?).await?;
+        fs::File::create(path).await?
+    };
 
-    fs::create_dir_all(PathBuf::from(&outpath).parent().context("Invalid path")?).await?;
-    fs::write(&outpath, output.bytes().await?).await?;
+    let total = output.content_length().map(|x| x + offset);
+    let mut done = offset;
+    if let Some(on_progress) = on_progress.as_mut() {
+        on_progress(done, total);
+    }
+    while let Some(chunk) = output.chunk().await? {
+        file.write_all(&chunk).await?;
+        done += chunk.len() as u64;
+        if let Some(on_progress) = on_progress.as_mut() {
+            on_progress(done, total);
+        }
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Unpack the downloaded database at `compressed` to `path` and verify it.
+///
+/// If the mirror publishes a checksum of the unpacked database at `{url}/{rev}.sha256`,
+/// the database has to match it.
+async fn unpack_database(
+    client: &reqwest::Client,
+    url: &str,
+    rev: &str,
+    compressed: &str,
+    path: &str,
+) -> Result<()> {
+    let actual = {
+        let (compressed, path) = (compressed.to_string(), path.to_string());
+        tokio::task::spawn_blocking(move || unpack(&compressed, &path))
+            .await
+            .map_err(std::io::Error::other)??
+    };
 
-    cleanup(&outpath, &cachejson).await?;
+    match published_checksum(client, url, rev).await {
+        Some(expected) if !expected.eq_ignore_ascii_case(&actual) => {
+            return Err(Error::Database(format!(
+                "Checksum mismatch for database {}: expected {}, got {}",
+                rev, expected, actual
+            )));
+        }
+        Some(_) => {}
+        None => debug!("No checksum published for database {}", rev),
+    }
 
-    Ok(outpath)
+    verify_database(path)
 }
 
-async fn cleanup(outpath: &str, cachejson: &DatabaseCache) -> Result<()> {
-    // Clean up old databases
-    let mut old_dbs = fs::read_dir(format!("{}/.cache/libxinux/", std::env::var("HOME")?)).await?;
-    while let Ok(Some(entry)) = old_dbs.next_entry().await {
-        let path = entry.path();
-        if path.extension().unwrap_or_default() == "db" {
-            let path_stem = path
-                .file_stem()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string();
-            if path != PathBuf::from(&outpath)
-                && path_stem != cachejson.current_rev
-                && path_stem != cachejson.new_rev
-            {
-                fs::remove_file(path).await?;
-            }
+/// Checksum the mirror at `url` publishes for the database of `rev`, if any.
+///
+/// A checksum that cannot be fetched is treated as missing, so that a flaky checksum
+/// endpoint does not throw away a complete download.
+async fn published_checksum(client: &reqwest::Client, url: &str, rev: &str) -> Option<String> {
+    let response = match client.get(format!("{}/{}.sha256", url, rev)).send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            debug!("Checksum of {} not available: {}", rev, response.status());
+            return None;
         }
+        Err(err) => {
+            warn!("Could not fetch the checksum of {}: {}", rev, err);
+            return None;
+        }
+    };
+    match response.text().await {
+        Ok(text) => text.split_whitespace().next().map(str::to_string),
+        Err(err) => {
+            warn!("Could not fetch the checksum of {}: {}", rev, err);
+            None
+        }
+    }
+}
+
+/// Header every SQLite database starts with.
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Decompress `compressed` to `path` and return the SHA-256 of the result.
+/// Servers that ignored `Accept-Encoding` send the database as is, which is copied.
+fn unpack(compressed: &str, path: &str) -> Result<String> {
+    let mut input = std::fs::File::open(compressed)?;
+    let mut header = [0; SQLITE_HEADER.len()];
+    let is_plain = input.read_exact(&mut header).is_ok() && header == SQLITE_HEADER;
+    input.rewind()?;
+
+    let mut output = HashWriter {
+        inner: std::io::BufWriter::new(std::fs::File::create(path)?),
+        hasher: Sha256::new(),
+    };
+    if is_plain {
+        std::io::copy(&mut input, &mut output)?;
+    } else {
+        std::io::copy(&mut brotli::Decompressor::new(input, 4096), &mut output)?;
+    }
+    output.flush()?;
+    Ok(format!("{:x}", output.hasher.finalize()))
+}
+
+/// Writer computing the SHA-256 of everything written through it.
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
     }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Check that the database at `path` is intact and has a supported schema.
+///
+/// This reads the whole database, so it is done once before a database is moved into
+/// the cache. [check_database] is enough for databases already there.
+pub fn verify_database(path: &str) -> Result<()> {
+    let db =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let result: String = db.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if result != "ok" {
+        return Err(Error::Database(format!(
+            "Integrity check of {} failed: {}",
+            path, result
+        )));
+    }
+    schema::check(&db)?;
     Ok(())
 }
 
-pub async fn database_connection() -> Result<rusqlite::Connection> {
-    let rev = get_revision().await?;
-    let path = fetch_database(&rev, DatabaseCacheEntry::Current).await?;
-    Ok(rusqlite::Connection::open(path)?)
+/// Whether `err`, returned by [check_database] or [verify_database], means that the
+/// database is damaged. Databases with an unsupported schema are intact and are kept,
+/// as they may be the only copy available offline.
+fn is_corrupt(err: &Error) -> bool {
+    matches!(err, Error::Sqlite(_) | Error::Database(_))
 }
 
-pub async fn database_connection_offline() -> Result<rusqlite::Connection> {
-    let mut dbs = fs::read_dir(format!("{}/.cache/libxinux/", std::env::var("HOME")?)).await?;
-    while let Ok(Some(entry)) = dbs.next_entry().await {
-        let path = entry.path();
-        if path.extension().unwrap_or_default() == "db" {
-            return Ok(rusqlite::Connection::open(path)?);
+/// Check that the cached database at `path` can be opened and has a supported schema,
+/// without reading all of it.
+pub fn check_database(path: &str) -> Result<()> {
+    let db =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    schema::check(&db)?;
+    Ok(())
+}
+
+pub async fn database_connection(
+    ctx: &Context,
+    on_progress: Option<DownloadProgress<'_>>,
+) -> Result<rusqlite::Connection> {
+    let rev = get_revision(ctx).await?;
+    let path = fetch_database(ctx, &rev, DatabaseCacheEntry::Current, on_progress).await?;
+    schema::open(&path)
+}
+
+/// Database opened by [database_connection_offline].
+#[derive(Debug)]
+pub struct OfflineDatabase {
+    pub connection: rusqlite::Connection,
+    /// Revision of the opened database.
+    pub rev: String,
+    /// Whether `rev` is the revision the system is running.
+    pub matches_system: bool,
+}
+
+/// Open a cached database without downloading anything.
+///
+/// Prefers the database of the revision the system is running, as far as it can be told
+/// without network access, and falls back to the most recently used cached revision.
+/// Corrupt databases are removed on the way, databases this version of libxinux cannot
+/// read are kept.
+pub async fn database_connection_offline(ctx: &Context) -> Result<OfflineDatabase> {
+    let system_rev = match get_local_revision(ctx).await {
+        Ok(rev) => Some(rev),
+        Err(err) => {
+            debug!("Could not determine the system revision: {}", err);
+            None
+        }
+    };
+    let mut candidates = cache::list(ctx)?
+        .into_iter()
+        .map(|x| x.rev)
+        .collect::<Vec<_>>();
+    if let Some(rev) = &system_rev {
+        if let Some(i) = candidates.iter().position(|x| x == rev) {
+            let rev = candidates.remove(i);
+            candidates.insert(0, rev);
         }
     }
-    Err(anyhow!("No database found"))
+
+    let mut last_err = None;
+    for rev in candidates {
+        let path = cache::database_path(ctx, &rev);
+        match check_database(&path) {
+            Ok(()) => {
+                let connection = schema::open(&path)?;
+                cache::touch(ctx, &rev)?;
+                return Ok(OfflineDatabase {
+                    matches_system: system_rev.as_deref() == Some(rev.as_str()),
+                    connection,
+                    rev,
+                });
+            }
+            Err(err) if is_corrupt(&err) => {
+                warn!("Removing corrupt database {}: {}", path, err);
+                fs::remove_file(&path).await?;
+            }
+            Err(err) => {
+                warn!("Skipping database {}: {}", path, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| Error::Database("No database found".to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::FakeRunner,
+        test_util::{Response, StandIn},
+    };
+    use std::sync::Arc;
+
+    fn context(name: &str) -> Context {
+        let dir = std::env::temp_dir().join(format!("libxinux-db-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy();
+        Context::builder()
+            .runner(Arc::new(FakeRunner::new()))
+            .arch("x86_64-linux")
+            .home(format!("{}/home", dir))
+            .cache_dir(format!("{}/cache", dir))
+            .config_dir(format!("{}/config", dir))
+            .is_nixos(false)
+            .build()
+            .unwrap()
+    }
+
+    /// Serve the database downloads of `ctx` from `url`.
+    fn use_mirror(ctx: &Context, url: &str) {
+        crate::config::configfile::LibXinuxConfig {
+            database_urls: vec![url.to_string()],
+            ..Default::default()
+        }
+        .write(ctx)
+        .unwrap();
+    }
+
+    /// Contents of a small database of the current schema.
+    fn database_bytes(ctx: &Context) -> Vec<u8> {
+        let path = format!("{}/source.sqlite", ctx.config_dir());
+        std::fs::create_dir_all(ctx.config_dir()).unwrap();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE pkgs (attribute TEXT, pname TEXT, version TEXT);
+                 CREATE TABLE meta (attribute TEXT, description TEXT, long_description TEXT,
+                     broken INTEGER, insecure INTEGER, unfree INTEGER);
+                 INSERT INTO pkgs VALUES ('hello', 'hello', '2.12.1');",
+            )
+            .unwrap();
+        std::fs::read(&path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn missing_checksum_keeps_download() {
+        let ctx = context("checksum");
+        // Only the database is served, the checksum request is refused
+        let server = StandIn::start(vec![Response::new(200, database_bytes(&ctx))]).await;
+        use_mirror(&ctx, &server.url);
+
+        let path = fetch_database(&ctx, "abc", DatabaseCacheEntry::Other, None)
+            .await
+            .unwrap();
+        assert!(schema::open(&path).is_ok());
+        assert!(server.requests()[0].starts_with("get /abc "));
+    }
+
+    /// Leave the first `len` bytes of `bytes` as a partial download of `rev`, from `source`.
+    fn partial_download(ctx: &Context, rev: &str, bytes: &[u8], source: PartialSource) {
+        let partial = format!("{}.br.part", cache::database_path(ctx, rev));
+        std::fs::create_dir_all(ctx.cache_dir()).unwrap();
+        std::fs::write(&partial, bytes).unwrap();
+        std::fs::write(
+            partial_source_path(&partial),
+            serde_json::to_vec(&source).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_from_same_mirror() {
+        let ctx = context("resume");
+        let bytes = database_bytes(&ctx);
+        let server = StandIn::start(vec![Response::new(206, &bytes[100..])]).await;
+        use_mirror(&ctx, &server.url);
+        let source = PartialSource {
+            url: server.url.clone(),
+            etag: None,
+        };
+        partial_download(&ctx, "abc", &bytes[..100], source);
+
+        let path = fetch_database(&ctx, "abc", DatabaseCacheEntry::Other, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        let request = &server.requests()[0];
+        assert!(request.contains("range: bytes=100-\r\n"));
+        assert!(!request.contains("if-range:"));
+    }
+
+    #[tokio::test]
+    async fn resumes_from_other_mirror_with_etag() {
+        let ctx = context("etag");
+        let bytes = database_bytes(&ctx);
+        let server = StandIn::start(vec![Response::new(206, &bytes[100..])]).await;
+        use_mirror(&ctx, &server.url);
+        let source = PartialSource {
+            url: "http://127.0.0.1:1".to_string(),
+            etag: Some("\"v1\"".to_string()),
+        };
+        partial_download(&ctx, "abc", &bytes[..100], source);
+
+        let path = fetch_database(&ctx, "abc", DatabaseCacheEntry::Other, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        let request = &server.requests()[0];
+        assert!(request.contains("range: bytes=100-\r\n"));
+        assert!(request.contains("if-range: \"v1\"\r\n"));
+    }
+
+    #[tokio::test]
+    async fn restarts_download_from_other_mirror() {
+        let ctx = context("restart");
+        let bytes = database_bytes(&ctx);
+        let server = StandIn::start(vec![Response::new(200, bytes.clone())]).await;
+        use_mirror(&ctx, &server.url);
+        let source = PartialSource {
+            url: "http://127.0.0.1:1".to_string(),
+            etag: None,
+        };
+        partial_download(&ctx, "abc", b"bytes from another mirror", source);
+
+        let path = fetch_database(&ctx, "abc", DatabaseCacheEntry::Other, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        assert!(!server.requests()[0].contains("range:"));
+        assert!(!PathBuf::from(format!("{}.br.part", path)).exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_incompatible_database() {
+        let ctx = context("incompatible");
+        std::fs::create_dir_all(ctx.cache_dir()).unwrap();
+        let path = cache::database_path(&ctx, "abc");
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch("PRAGMA user_version = 99; CREATE TABLE packages (attribute TEXT);")
+            .unwrap();
+
+        let err = database_connection_offline(&ctx).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::IncompatibleDatabase { version: 99, .. }
+        ));
+        let err = fetch_database(&ctx, "abc", DatabaseCacheEntry::Other, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::IncompatibleDatabase { version: 99, .. }
+        ));
+        assert!(PathBuf::from(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn removes_corrupt_database() {
+        let ctx = context("corrupt");
+        std::fs::create_dir_all(ctx.cache_dir()).unwrap();
+        let path = cache::database_path(&ctx, "abc");
+        std::fs::write(&path, "not a database").unwrap();
+
+        assert!(database_connection_offline(&ctx).await.is_err());
+        assert!(!PathBuf::from(&path).exists());
+    }
 }
@@ -0,0 +1,126 @@
+//! # Revision diff
+//!
+//! Compares the package sets of two nixpkgs revisions, e.g. to show what a channel
+//! update brings before it is applied.
+
+use super::{
+    database::{fetch_database, DatabaseCacheEntry},
+    schema,
+};
+use crate::{Context, Package, PackageAttr, PackageUpdate, Result};
+use rusqlite::Connection;
+use std::collections::HashMap;
+
+/// Differences between the package sets of two revisions.
+#[derive(Debug, Clone, Default)]
+pub struct RevisionDiff {
+    /// Packages only present in the new revision.
+    pub added: Vec<Package>,
+    /// Packages only present in the old revision.
+    pub removed: Vec<Package>,
+    /// Packages whose version changed.
+    pub updated: Vec<PackageUpdate>,
+    /// Packages marked broken in the new revision but not in the old one.
+    pub newly_broken: Vec<Package>,
+    /// Packages marked insecure in the new revision but not in the old one.
+    pub newly_insecure: Vec<Package>,
+}
+
+struct Entry {
+    pname: Option<String>,
+    version: Option<String>,
+    broken: bool,
+    insecure: bool,
+}
+
+impl Entry {
+    fn package(&self, attr: &str) -> Package {
+        Package {
+            attr: PackageAttr::NixPkgs {
+                attr: attr.to_string(),
+                outputs: None,
+            },
+            pname: self.pname.clone(),
+            version: self.version.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Compare the packages of `old_rev` and `new_rev`, fetching their databases if needed.
+///
+/// The databases are cached without replacing the ones of the installed system or of
+/// the latest revision, so comparing arbitrary revisions leaves those in place.
+pub async fn diff(ctx: &Context, old_rev: &str, new_rev: &str) -> Result<RevisionDiff> {
+    let old = fetch_database(ctx, old_rev, DatabaseCacheEntry::Other, None).await?;
+    let new = fetch_database(ctx, new_rev, DatabaseCacheEntry::Other, None).await?;
+    tokio::task::spawn_blocking(move || diff_databases(&schema::open(&old)?, &schema::open(&new)?))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Compare the packages of two open metadata databases.
+pub fn diff_databases(old: &Connection, new: &Connection) -> Result<RevisionDiff> {
+    let old = entries(old)?;
+    let new = entries(new)?;
+    let mut diff = RevisionDiff::default();
+
+    for (attr, entry) in &new {
+        let Some(previous) = old.get(attr) else {
+            diff.added.push(entry.package(attr));
+            continue;
+        };
+        if let (Some(old_version), Some(new_version)) = (&previous.version, &entry.version) {
+            if old_version != new_version {
+                diff.updated.push(PackageUpdate::new(
+                    PackageAttr::NixPkgs {
+                        attr: attr.clone(),
+                        outputs: None,
+                    },
+                    old_version.clone(),
+                    new_version.clone(),
+                ));
+            }
+        }
+        if entry.broken && !previous.broken {
+            diff.newly_broken.push(entry.package(attr));
+        }
+        if entry.insecure && !previous.insecure {
+            diff.newly_insecure.push(entry.package(attr));
+        }
+    }
+    for (attr, entry) in &old {
+        if !new.contains_key(attr) {
+            diff.removed.push(entry.package(attr));
+        }
+    }
+
+    for packages in [
+        &mut diff.added,
+        &mut diff.removed,
+        &mut diff.newly_broken,
+        &mut diff.newly_insecure,
+    ] {
+        packages.sort_by_cached_key(|x| x.attr.to_string());
+    }
+    diff.updated.sort_by_cached_key(|x| x.attr.to_string());
+    Ok(diff)
+}
+
+fn entries(db: &Connection) -> Result<HashMap<String, Entry>> {
+    let mut stmt = db.prepare("SELECT pkgs.attribute, pkgs.pname, pkgs.version, meta.broken, meta.insecure FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute")?;
+    let entries = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                Entry {
+                    pname: row.get(1)?,
+                    version: row.get(2)?,
+                    broken: row.get::<_, Option<bool>>(3)?.unwrap_or(false),
+                    insecure: row.get::<_, Option<bool>>(4)?.unwrap_or(false),
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(entries)
+}
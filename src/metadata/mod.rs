@@ -1,3 +1,11 @@
+pub mod builder;
+pub mod cache;
 pub mod database;
+pub mod diff;
+pub mod programs;
+pub mod query;
 pub mod revision;
+pub mod schema;
 pub mod search;
+
+pub use diff::diff;
@@ -0,0 +1,111 @@
+//! # Programs
+//!
+//! Find the packages providing a command, like `command-not-found` does.
+//!
+//! NixOS channels ship a `programs.sqlite` database listing the binaries of every
+//! package, which is used when available. Otherwise the metadata database is consulted,
+//! matching the command against `meta.mainProgram` where the database has it, and against
+//! package names.
+
+use super::{revision::channel::channel_dirs, schema};
+use crate::{Context, Result};
+use rusqlite::{Connection, OpenFlags};
+use std::collections::HashMap;
+
+/// Path of the `programs.sqlite` of the first channel that has one.
+pub fn programs_database(ctx: &Context) -> Option<String> {
+    for dir in channel_dirs(ctx) {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut channels = entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.path().join("programs.sqlite"))
+            .filter(|x| x.exists())
+            .collect::<Vec<_>>();
+        channels.sort();
+        if let Some(path) = channels.first() {
+            return Some(path.to_string_lossy().to_string());
+        }
+    }
+    None
+}
+
+fn open_programs(path: &str) -> Result<Connection> {
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?)
+}
+
+/// Attributes of the packages providing the command `program` for the system of `ctx`.
+///
+/// `db` is the metadata database, used if no channel provides `programs.sqlite`.
+pub fn providers(ctx: &Context, db: &Connection, program: &str) -> Result<Vec<String>> {
+    if let Some(path) = programs_database(ctx) {
+        let programs = open_programs(&path)?;
+        let mut stmt = programs.prepare(
+            "SELECT DISTINCT package FROM Programs WHERE name = ? AND system = ? ORDER BY package",
+        )?;
+        let attrs = stmt
+            .query_map([program, ctx.arch()], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        return Ok(attrs);
+    }
+
+    let condition = if schema::has_column(db, "meta", "main_program")? {
+        "meta.main_program = ?1 OR (meta.main_program IS NULL AND pkgs.pname = ?1)"
+    } else {
+        "pkgs.pname = ?1"
+    };
+    let mut stmt = db.prepare(&format!(
+        "SELECT pkgs.attribute FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute \
+         WHERE {} ORDER BY pkgs.attribute",
+        condition
+    ))?;
+    let attrs = stmt
+        .query_map([program], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(attrs)
+}
+
+/// Commands provided by each package, by attribute, for indexing.
+pub(crate) fn programs_by_attribute(
+    ctx: &Context,
+    db: &Connection,
+) -> Result<HashMap<String, Vec<String>>> {
+    let mut programs: HashMap<String, Vec<String>> = HashMap::new();
+    let mut add = |attr: String, program: String| {
+        let entry = programs.entry(attr).or_default();
+        if !entry.contains(&program) {
+            entry.push(program);
+        }
+    };
+
+    if let Some(path) = programs_database(ctx) {
+        let programs_db = open_programs(&path)?;
+        let mut stmt =
+            programs_db.prepare("SELECT package, name FROM Programs WHERE system = ?")?;
+        let mut rows = stmt.query([ctx.arch()])?;
+        while let Some(row) = rows.next()? {
+            add(row.get(0)?, row.get(1)?);
+        }
+    }
+
+    let main_program = if schema::has_column(db, "meta", "main_program")? {
+        "meta.main_program"
+    } else {
+        "NULL"
+    };
+    let mut stmt = db.prepare(&format!(
+        "SELECT pkgs.attribute, COALESCE({}, pkgs.pname) FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute",
+        main_program
+    ))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if let (attr, Some(program)) = (row.get(0)?, row.get::<_, Option<String>>(1)?) {
+            add(attr, program);
+        }
+    }
+    Ok(programs)
+}
@@ -0,0 +1,154 @@
+//! # Package queries
+//!
+//! Typed lookups in the metadata database. Metadata that not every database provides,
+//! such as homepages or maintainers, is left empty when the database lacks the column.
+//!
+//! Connections have to come from [schema::open] or [database_connection], which prepare
+//! them for every supported schema version.
+//!
+//! [database_connection]: super::database::database_connection
+//!
+//! ```no_run
+//! use libxinux::metadata::{database::database_connection, query};
+//!
+//! # async fn example() -> libxinux::Result<()> {
+//! let ctx = libxinux::Context::new()?;
+//! let db = database_connection(&ctx, None).await?;
+//! if let Some(info) = query::package(&db, "hello")? {
+//!     println!("{} {}: {:?}", info.pname, info.version, info.description);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::schema;
+use crate::{Package, PackageAttr, Result};
+use rusqlite::{Connection, Row};
+
+/// Everything the metadata database knows about a package.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub attribute: String,
+    pub pname: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub long_description: Option<String>,
+    pub homepage: Option<String>,
+    /// SPDX identifiers or short names of the licenses.
+    pub license: Vec<String>,
+    pub maintainers: Vec<String>,
+    /// Nix system types the package is available on.
+    pub platforms: Vec<String>,
+    /// Location of the package definition in nixpkgs, as `file:line`.
+    pub position: Option<String>,
+    pub broken: bool,
+    pub insecure: bool,
+    pub unfree: bool,
+}
+
+impl PackageInfo {
+    pub fn to_package(&self) -> Package {
+        Package {
+            attr: PackageAttr::NixPkgs {
+                attr: self.attribute.clone(),
+                outputs: None,
+            },
+            pname: Some(self.pname.clone()),
+            version: Some(self.version.clone()).filter(|x| !x.is_empty()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Columns of `meta` that only some databases have.
+const OPTIONAL_COLUMNS: &[&str] = &[
+    "homepage",
+    "license",
+    "maintainers",
+    "platforms",
+    "position",
+];
+
+/// Query selecting every [PackageInfo] field, followed by `condition`.
+fn select(db: &Connection, condition: &str) -> Result<String> {
+    let present = schema::columns(db, "meta")?;
+    let optional = OPTIONAL_COLUMNS
+        .iter()
+        .map(|column| {
+            if present.iter().any(|x| x == column) {
+                format!("meta.{}", column)
+            } else {
+                "NULL".to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(format!(
+        "SELECT pkgs.attribute, pkgs.pname, pkgs.version, meta.description, meta.long_description, \
+         meta.broken, meta.insecure, meta.unfree, {} \
+         FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute WHERE {}",
+        optional, condition
+    ))
+}
+
+/// Parse a list column, stored either as a JSON array or as comma separated values.
+pub(crate) fn list(value: Option<String>) -> Vec<String> {
+    let Some(value) = value.filter(|x| !x.trim().is_empty()) else {
+        return vec![];
+    };
+    if value.starts_with('[') {
+        if let Ok(list) = serde_json::from_str(&value) {
+            return list;
+        }
+    }
+    value
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn package_info(row: &Row) -> rusqlite::Result<PackageInfo> {
+    Ok(PackageInfo {
+        attribute: row.get(0)?,
+        pname: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+        version: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+        description: row.get(3)?,
+        long_description: row.get(4)?,
+        broken: row.get::<_, Option<bool>>(5)?.unwrap_or(false),
+        insecure: row.get::<_, Option<bool>>(6)?.unwrap_or(false),
+        unfree: row.get::<_, Option<bool>>(7)?.unwrap_or(false),
+        homepage: row.get(8)?,
+        license: list(row.get(9)?),
+        maintainers: list(row.get(10)?),
+        platforms: list(row.get(11)?),
+        position: row.get(12)?,
+    })
+}
+
+/// The package at attribute path `attr`, e.g. `hello` or `python3Packages.requests`.
+pub fn package(db: &Connection, attr: &str) -> Result<Option<PackageInfo>> {
+    let mut stmt = db.prepare_cached(&select(db, "pkgs.attribute = ?")?)?;
+    let mut rows = stmt.query([attr])?;
+    Ok(rows.next()?.map(package_info).transpose()?)
+}
+
+/// Every package with the name `pname`, ordered by attribute.
+pub fn packages_by_pname(db: &Connection, pname: &str) -> Result<Vec<PackageInfo>> {
+    let mut stmt = db.prepare_cached(&select(db, "pkgs.pname = ? ORDER BY pkgs.attribute")?)?;
+    let packages = stmt
+        .query_map([pname], package_info)?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(packages)
+}
+
+/// Attribute paths starting with `prefix`, e.g. every attribute in `python3Packages.`.
+pub fn attributes_with_prefix(db: &Connection, prefix: &str) -> Result<Vec<String>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT attribute FROM pkgs WHERE substr(attribute, 1, length(?1)) = ?1 ORDER BY attribute",
+    )?;
+    let attributes = stmt
+        .query_map([prefix], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(attributes)
+}
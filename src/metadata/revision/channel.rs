@@ -0,0 +1,86 @@
+//! # Channel revisions
+//!
+//! Systems managed with `nix-channel` have no flake to read the nixpkgs revision from,
+//! but every channel records the commit it was built from: `.git-revision` holds the
+//! full hash, and `.version-suffix` (e.g. `.24.05.1234.abcdef1`) ends with the short one.
+//! Both are read from the channels profile, so no network access is needed.
+
+use crate::{metadata::cache, Context, Error, Result};
+use log::debug;
+use std::path::Path;
+
+/// Names of the channels nixpkgs is usually subscribed as, in order of preference.
+pub const NIXPKGS_CHANNELS: &[&str] = &["nixpkgs", "nixos"];
+
+/// Channel directories of the user and of the system, most specific first.
+pub(crate) fn channel_dirs(ctx: &Context) -> Vec<String> {
+    let mut dirs = vec![];
+    if let Ok(home) = ctx.home() {
+        dirs.push(format!("{}/.nix-defexpr/channels", home));
+    }
+    dirs.push("/nix/var/nix/profiles/per-user/root/channels".to_string());
+    dirs
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+}
+
+/// Revision of the channel in the directory `channel`.
+///
+/// If the channel only has a `.version-suffix`, the short hash it ends with is matched
+/// against the revisions in the cache.
+pub fn read_channel_revision(ctx: &Context, channel: &Path) -> Result<String> {
+    if let Some(rev) = read_trimmed(&channel.join(".git-revision")) {
+        return Ok(rev);
+    }
+    let suffix = read_trimmed(&channel.join(".version-suffix")).ok_or_else(|| {
+        Error::Revision(format!("{} has no revision information", channel.display()))
+    })?;
+    // .24.05.1234.abcdef1 or pre1234.abcdef1
+    let short = suffix
+        .rsplit('.')
+        .next()
+        .filter(|x| x.len() >= 7 && x.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| {
+            Error::Revision(format!(
+                "No revision in {}/.version-suffix: {}",
+                channel.display(),
+                suffix
+            ))
+        })?;
+    cache::list(ctx)?
+        .into_iter()
+        .map(|x| x.rev)
+        .find(|x| x.starts_with(short))
+        .ok_or_else(|| {
+            Error::Revision(format!(
+                "Channel {} is at {}, which is not in the cache",
+                channel.display(),
+                short
+            ))
+        })
+}
+
+/// Revision of nixpkgs from the first subscribed channel in [NIXPKGS_CHANNELS].
+///
+/// The channels of the user are preferred over those of root, as they are the ones
+/// `nix-env` installs from.
+pub fn get_channel_revision(ctx: &Context) -> Result<String> {
+    for dir in channel_dirs(ctx) {
+        for name in NIXPKGS_CHANNELS {
+            let channel = Path::new(&dir).join(name);
+            if !channel.exists() {
+                continue;
+            }
+            match read_channel_revision(ctx, &channel) {
+                Ok(rev) => return Ok(rev),
+                Err(err) => debug!("Not using channel {}: {}", channel.display(), err),
+            }
+        }
+    }
+    Err(Error::Revision("No nixpkgs channel found".to_string()))
+}
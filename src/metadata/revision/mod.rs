@@ -0,0 +1,350 @@
+pub mod channel;
+pub mod source;
+
+use log::debug;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{
+    command::CommandSpec,
+    config::configfile::{get_config, get_user_pkg_type, release_branch, UserPkgType},
+    flakeref::{FlakeRef, FlakeSource},
+    Context, Error, Result,
+};
+use source::{GitHost, Repository};
+
+#[derive(Debug, Deserialize)]
+struct NixosVersion {
+    #[serde(rename = "nixpkgsRevision")]
+    nixpkgs_revision: String,
+    #[serde(rename = "nixosVersion")]
+    nixos_version: String,
+}
+
+async fn nixos_version(ctx: &Context) -> Result<NixosVersion> {
+    let output = ctx
+        .runner()
+        .run(&CommandSpec::new("nixos-version").arg("--json"))
+        .await?
+        .stdout_string()?;
+    Ok(serde_json::from_str(&output)?)
+}
+
+pub async fn get_revision(ctx: &Context) -> Result<String> {
+    if ctx.is_nixos() {
+        Ok(nixos_version(ctx).await?.nixpkgs_revision)
+    } else {
+        if let Some(rev) = configured_flake_revision(ctx) {
+            return Ok(rev);
+        }
+        if let Some(rev) = env_channel_revision(ctx) {
+            return Ok(rev);
+        }
+        match registry_nixpkgs(ctx, &["global"]).await {
+            Ok(flake) => resolve_flakeref(ctx, &flake).await,
+            Err(err) => channel_revision(ctx).ok_or(err),
+        }
+    }
+}
+
+/// Revision of the installed system, from local sources only.
+///
+/// Like [get_revision], but a registry entry is only used if it is locked to a revision,
+/// so the [RevisionSource](source::RevisionSource) is never asked.
+pub async fn get_local_revision(ctx: &Context) -> Result<String> {
+    if ctx.is_nixos() {
+        return Ok(nixos_version(ctx).await?.nixpkgs_revision);
+    }
+    if let Some(rev) = configured_flake_revision(ctx) {
+        return Ok(rev);
+    }
+    if let Some(rev) = env_channel_revision(ctx) {
+        return Ok(rev);
+    }
+    let registered = registry_nixpkgs(ctx, &["global"]).await.and_then(|flake| {
+        flake
+            .rev()
+            .map(str::to_string)
+            .ok_or_else(|| Error::Revision("The nixpkgs registry entry is not locked".to_string()))
+    });
+    match registered {
+        Ok(rev) => Ok(rev),
+        Err(err) => channel_revision(ctx).ok_or(err),
+    }
+}
+
+pub async fn get_profile_revision(ctx: &Context) -> Result<String> {
+    if let Some(rev) = configured_flake_revision(ctx) {
+        return Ok(rev);
+    }
+    match registry_nixpkgs(ctx, &["global", "system"]).await {
+        Ok(flake) => resolve_flakeref(ctx, &flake).await,
+        Err(err) => channel_revision(ctx).ok_or(err),
+    }
+}
+
+/// Revision of the nixpkgs channel, for users installing packages with `nix-env`.
+///
+/// Users of `nix profile` install from the flake registry, which may be far ahead of a
+/// channel left over on the system, so the channel is only a fallback for them.
+fn env_channel_revision(ctx: &Context) -> Option<String> {
+    if get_user_pkg_type(ctx) == UserPkgType::Env {
+        channel_revision(ctx)
+    } else {
+        None
+    }
+}
+
+/// Revision of the nixpkgs channel, on systems using `nix-channel`.
+fn channel_revision(ctx: &Context) -> Option<String> {
+    match channel::get_channel_revision(ctx) {
+        Ok(rev) => Some(rev),
+        Err(err) => {
+            debug!("Not using a channel revision: {}", err);
+            None
+        }
+    }
+}
+
+/// Path of the `flake.lock` next to the configured flake.
+fn configured_flake_lock(ctx: &Context) -> Option<String> {
+    let flake_dir = get_config(ctx).ok()?.get_flake_dir().ok()?;
+    Some(format!("{}/flake.lock", flake_dir))
+}
+
+/// Revision of nixpkgs locked by the flake in [LibXinuxConfig::flake](crate::config::configfile::LibXinuxConfig::flake), if one is configured.
+fn configured_flake_revision(ctx: &Context) -> Option<String> {
+    let path = configured_flake_lock(ctx)?;
+    match get_flake_lock_revision(&path, "nixpkgs") {
+        Ok(rev) => Some(rev),
+        Err(err) => {
+            debug!("Not using the revision of {}: {}", path, err);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FlakeLock {
+    nodes: HashMap<String, LockNode>,
+    root: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LockNode {
+    #[serde(default)]
+    inputs: HashMap<String, LockInput>,
+    locked: Option<LockedRef>,
+    original: Option<OriginalRef>,
+}
+
+/// An input of a node, either the name of another node or a `follows` path from the root.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LockInput {
+    Node(String),
+    Follows(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct LockedRef {
+    rev: Option<String>,
+}
+
+/// The reference an input was written as, before locking.
+#[derive(Debug, Deserialize)]
+struct OriginalRef {
+    #[serde(rename = "type")]
+    kind: String,
+    owner: Option<String>,
+    repo: Option<String>,
+    #[serde(rename = "ref")]
+    reference: Option<String>,
+}
+
+impl FlakeLock {
+    /// Name of the node the input at `path` from the root refers to, following `follows`.
+    fn resolve(&self, path: &[String], depth: usize) -> Result<&str> {
+        // Lock files are acyclic, but a corrupt one should not hang
+        if depth > self.nodes.len() {
+            return Err(Error::Revision("Cyclic follows in flake.lock".to_string()));
+        }
+        let mut node = self.root.as_str();
+        for input in path {
+            let inputs = &self
+                .nodes
+                .get(node)
+                .ok_or_else(|| Error::Revision(format!("No node {} in flake.lock", node)))?
+                .inputs;
+            node = match inputs.get(input) {
+                Some(LockInput::Node(name)) => name,
+                Some(LockInput::Follows(path)) => self.resolve(path, depth + 1)?,
+                None => return Err(Error::Revision(format!("No input {} in flake.lock", input))),
+            };
+        }
+        Ok(node)
+    }
+}
+
+/// Node of the root input `input` in the `flake.lock` at `path`.
+fn read_lock_node(path: &str, input: &str) -> Result<LockNode> {
+    let mut lock: FlakeLock = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let node = lock.resolve(&[input.to_string()], 0)?.to_string();
+    lock.nodes
+        .remove(&node)
+        .ok_or_else(|| Error::Revision(format!("No node {} in flake.lock", node)))
+}
+
+/// Revision the root input `input` (usually `nixpkgs`) is locked to in the `flake.lock` at `path`.
+pub fn get_flake_lock_revision(path: &str, input: &str) -> Result<String> {
+    read_lock_node(path, input)?
+        .locked
+        .as_ref()
+        .and_then(|x| x.rev.clone())
+        .ok_or_else(|| {
+            Error::Revision(format!(
+                "Input {} of {} has no locked revision",
+                input, path
+            ))
+        })
+}
+
+/// The `nixpkgs` entry of the flake registry, from the first of `registries` that has one.
+async fn registry_nixpkgs(ctx: &Context, registries: &[&str]) -> Result<FlakeRef> {
+    let output = ctx
+        .runner()
+        .run(&CommandSpec::new("nix").arg("registry").arg("list"))
+        .await?
+        .stdout_string()?;
+    let entries = output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(registry), Some("flake:nixpkgs"), Some(target)) => Some((registry, target)),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    let target = registries
+        .iter()
+        .find_map(|registry| entries.iter().find(|(x, _)| x == registry))
+        .map(|(_, target)| *target)
+        .ok_or_else(|| Error::Revision("No nixpkgs flake found".to_string()))?;
+    target
+        .parse()
+        .map_err(|_| Error::Revision("Invalid registry entry".to_string()))
+}
+
+/// Repository and branch the root input `input` of the `flake.lock` at `path` follows,
+/// if it is a branch on GitHub or GitLab.
+pub fn get_flake_lock_branch(path: &str, input: &str) -> Result<(Repository, String)> {
+    let node = read_lock_node(path, input)?;
+    let original = node.original.ok_or_else(|| {
+        Error::Revision(format!(
+            "Input {} of {} has no original reference",
+            input, path
+        ))
+    })?;
+    let host = match original.kind.as_str() {
+        "github" => GitHost::GitHub,
+        "gitlab" => GitHost::GitLab,
+        kind => {
+            return Err(Error::Revision(format!(
+                "Input {} of {} is of type {}, not a GitHub or GitLab repository",
+                input, path, kind
+            )))
+        }
+    };
+    match (original.owner, original.repo, original.reference) {
+        (Some(owner), Some(repo), Some(branch)) => Ok((Repository::new(host, owner, repo), branch)),
+        _ => Err(Error::Revision(format!(
+            "Input {} of {} does not follow a branch",
+            input, path
+        ))),
+    }
+}
+
+/// Revision `flake` points to. Locked references like `path:/nix/store/...?rev=...` are
+/// read directly, branches on GitHub and GitLab are looked up.
+async fn resolve_flakeref(ctx: &Context, flake: &FlakeRef) -> Result<String> {
+    if let Some(rev) = flake.rev() {
+        return Ok(rev.to_string());
+    }
+    match Repository::from_flakeref(flake) {
+        Some((repository, Some(branch))) => {
+            ctx.revision_source().resolve(&repository, &branch).await
+        }
+        Some((repository, None)) => Err(Error::Revision(format!(
+            "No branch given for {}",
+            repository
+        ))),
+        None if matches!(flake.source, FlakeSource::Path { .. }) => {
+            Err(Error::Revision("No rev found".to_string()))
+        }
+        None => Err(Error::Revision("Invalid nixpkgs flake path".to_string())),
+    }
+}
+
+/// Repository and branch that `nixpkgs` tracks in the configured flake, if it is on
+/// GitHub or GitLab.
+fn configured_flake_branch(ctx: &Context) -> Option<(Repository, String)> {
+    let path = configured_flake_lock(ctx)?;
+    match get_flake_lock_branch(&path, "nixpkgs") {
+        Ok(branch) => Some(branch),
+        Err(err) => {
+            debug!("Not following the branch of {}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Latest revision of the branch the system follows.
+///
+/// A nixpkgs input of the configured flake that tracks a GitHub or GitLab branch, such as
+/// `github:xinux-org/nixpkgs/nixos-unstable`, is followed directly. Otherwise NixOS follows
+/// the branch of its release (see [release_branch]) and other systems the branch of the
+/// `nixpkgs` registry entry, or `nixpkgs-unstable` of `NixOS/nixpkgs`.
+pub async fn get_latest_nixpkgs_revision(ctx: &Context) -> Result<String> {
+    if let Some((repository, branch)) = configured_flake_branch(ctx) {
+        return ctx.revision_source().resolve(&repository, &branch).await;
+    }
+    if ctx.is_nixos() {
+        let version = nixos_version(ctx).await?;
+
+        // 24.11.12345678.abcdefg -> 24.11
+        let release = version
+            .nixos_version
+            .split(".")
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(".");
+
+        let repository = Repository::nixpkgs();
+        match ctx
+            .revision_source()
+            .resolve(&repository, &release_branch(ctx, &release))
+            .await
+        {
+            Ok(rev) => Ok(rev),
+            Err(err) => {
+                debug!("Falling back to nixos-unstable: {}", err);
+                ctx.revision_source()
+                    .resolve(&repository, "nixos-unstable")
+                    .await
+            }
+        }
+    } else {
+        let registered = match registry_nixpkgs(ctx, &["global"]).await {
+            Ok(flake) => Repository::from_flakeref(&flake)
+                .and_then(|(repository, branch)| Some((repository, branch?))),
+            Err(err) => {
+                debug!("Not following the registry: {}", err);
+                None
+            }
+        };
+        let (repository, branch) =
+            registered.unwrap_or_else(|| (Repository::nixpkgs(), "nixpkgs-unstable".to_string()));
+        ctx.revision_source().resolve(&repository, &branch).await
+    }
+}
@@ -0,0 +1,717 @@
+//! # Revision sources
+//!
+//! A [RevisionSource] tells which commit a branch of a nixpkgs [Repository] currently
+//! points to. libxinux uses the one set with
+//! [ContextBuilder::revision_source](crate::ContextBuilder::revision_source), which
+//! defaults to [default_source]: the GitHub or GitLab API, falling back to the NixOS
+//! channel server for `NixOS/nixpkgs`, with answers cached on disk.
+//!
+//! Every network source takes its base URL as a parameter, so that it can be pointed
+//! at a mirror or a local stand-in.
+//!
+//! ```
+//! use libxinux::metadata::revision::source::{FixedSource, Repository, RevisionSource};
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let source = FixedSource::new("0123456789abcdef0123456789abcdef01234567");
+//! let fork: Repository = "github:xinux-org/nixpkgs".parse().unwrap();
+//! assert_eq!(
+//!     source.resolve(&fork, "nixos-unstable").await.unwrap(),
+//!     "0123456789abcdef0123456789abcdef01234567"
+//! );
+//! # });
+//! ```
+
+use super::get_flake_lock_revision;
+use crate::{
+    flakeref::{FlakeRef, FlakeSource},
+    Error, Result,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, warn};
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// GitLab API used when no other is given.
+pub const DEFAULT_GITLAB_API_URL: &str = "https://gitlab.com/api/v4";
+/// GitHub API used when no other is given.
+pub const DEFAULT_GITHUB_API_URL: &str = "https://api.github.com";
+/// NixOS channel server used when no other is given.
+pub const DEFAULT_CHANNELS_URL: &str = "https://channels.nixos.org";
+/// Environment variable holding a token for the GitHub API.
+pub const GITHUB_TOKEN_ENV: &str = "GITHUB_TOKEN";
+/// Environment variable holding a token for the GitLab API.
+pub const GITLAB_TOKEN_ENV: &str = "GITLAB_TOKEN";
+/// How long a request may take before it is abandoned.
+pub const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+/// How long resolved revisions are cached by [default_source].
+pub const DEFAULT_TTL: Duration = Duration::hours(1);
+
+/// How many times failed requests are repeated.
+const RETRIES: u32 = 2;
+/// Delay before the first retry, doubled for every further one.
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Forge hosting a [Repository].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GitHost {
+    GitHub,
+    GitLab,
+}
+
+/// A nixpkgs repository, e.g. `github:NixOS/nixpkgs` or `github:xinux-org/nixpkgs`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Repository {
+    pub host: GitHost,
+    pub owner: String,
+    pub repo: String,
+}
+
+impl Repository {
+    pub fn new(host: GitHost, owner: impl Into<String>, repo: impl Into<String>) -> Self {
+        Self {
+            host,
+            owner: owner.into(),
+            repo: repo.into(),
+        }
+    }
+
+    /// The upstream `github:NixOS/nixpkgs`.
+    pub fn nixpkgs() -> Self {
+        Self::new(GitHost::GitHub, "NixOS", "nixpkgs")
+    }
+
+    /// Repository and branch of a `github:` or `gitlab:` flake reference, if it has any.
+    pub fn from_flakeref(flake: &FlakeRef) -> Option<(Self, Option<String>)> {
+        let (host, owner, repo) = match &flake.source {
+            FlakeSource::GitHub { owner, repo, .. } => (GitHost::GitHub, owner, repo),
+            FlakeSource::GitLab { owner, repo, .. } => (GitHost::GitLab, owner, repo),
+            _ => return None,
+        };
+        Some((
+            Self::new(host, owner, repo),
+            flake.git_ref().map(str::to_string),
+        ))
+    }
+}
+
+impl Default for Repository {
+    fn default() -> Self {
+        Self::nixpkgs()
+    }
+}
+
+impl fmt::Display for Repository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = match self.host {
+            GitHost::GitHub => "github",
+            GitHost::GitLab => "gitlab",
+        };
+        write!(f, "{}:{}/{}", host, self.owner, self.repo)
+    }
+}
+
+impl FromStr for Repository {
+    type Err = Error;
+
+    /// Parse `github:owner/repo` or `gitlab:owner/repo`, ignoring any branch.
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_flakeref(&s.parse()?)
+            .map(|(repository, _)| repository)
+            .ok_or_else(|| {
+                Error::InvalidFlakeRef(format!("{} is not a GitHub or GitLab repository", s))
+            })
+    }
+}
+
+/// Resolves branches of nixpkgs repositories to commit hashes.
+#[async_trait]
+pub trait RevisionSource: Send + Sync {
+    /// Commit hash `branch` (e.g. `nixos-24.05` or `nixpkgs-unstable`) of `repository`
+    /// currently points to.
+    async fn resolve(&self, repository: &Repository, branch: &str) -> Result<String>;
+
+    /// Identifies the answer for `branch` of `repository`, used as key when caching it.
+    fn describe(&self, repository: &Repository, branch: &str) -> String;
+}
+
+fn unsupported(source: &str, repository: &Repository) -> Error {
+    Error::Revision(format!(
+        "{} cannot resolve branches of {}",
+        source, repository
+    ))
+}
+
+/// Send a GET request, repeating it after network errors, server errors and rate limiting.
+async fn get(
+    client: &reqwest::Client,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response> {
+    let mut attempt = 0;
+    loop {
+        let request = request
+            .try_clone()
+            .ok_or_else(|| Error::Revision("Request cannot be repeated".to_string()))?
+            .build()?;
+        let url = request.url().to_string();
+        match client.execute(request).await {
+            Ok(response)
+                if attempt >= RETRIES
+                    || !(response.status().is_server_error()
+                        || response.status() == StatusCode::TOO_MANY_REQUESTS) =>
+            {
+                return Ok(response)
+            }
+            Err(err) if attempt >= RETRIES => return Err(err.into()),
+            Ok(response) => debug!("Request to {} failed: {}", url, response.status()),
+            Err(err) => debug!("Request to {} failed: {}", url, err),
+        }
+        tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+fn client(timeout: std::time::Duration) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .user_agent("libxinux")
+        .timeout(timeout)
+        .build()?)
+}
+
+/// Looks branches up with the commits API of GitHub.
+#[derive(Debug, Clone)]
+pub struct GitHubSource {
+    base_url: String,
+    token: Option<String>,
+    timeout: std::time::Duration,
+}
+
+impl Default for GitHubSource {
+    /// [DEFAULT_GITHUB_API_URL], authenticated with `GITHUB_TOKEN` if set.
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_GITHUB_API_URL.to_string(),
+            token: std::env::var(GITHUB_TOKEN_ENV)
+                .ok()
+                .filter(|x| !x.is_empty()),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl GitHubSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Token sent as bearer authorization. Defaults to `GITHUB_TOKEN`.
+    pub fn token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GhResponse {
+    sha: String,
+}
+
+#[async_trait]
+impl RevisionSource for GitHubSource {
+    async fn resolve(&self, repository: &Repository, branch: &str) -> Result<String> {
+        if repository.host != GitHost::GitHub {
+            return Err(unsupported("GitHub", repository));
+        }
+        let client = client(self.timeout)?;
+        let mut request = client
+            .get(format!(
+                "{}/repos/{}/{}/commits/{}",
+                self.base_url, repository.owner, repository.repo, branch
+            ))
+            .header(header::ACCEPT, "application/vnd.github+json");
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = get(&client, request).await?;
+        if !response.status().is_success() {
+            return Err(Error::Revision(format!(
+                "GitHub could not resolve {} of {}: {}",
+                branch,
+                repository,
+                response.status()
+            )));
+        }
+        Ok(response.json::<GhResponse>().await?.sha)
+    }
+
+    fn describe(&self, repository: &Repository, branch: &str) -> String {
+        format!("{}/{}@{}", repository, branch, self.base_url)
+    }
+}
+
+/// Looks branches up with the commits API of GitLab.
+#[derive(Debug, Clone)]
+pub struct GitLabSource {
+    base_url: String,
+    token: Option<String>,
+    timeout: std::time::Duration,
+}
+
+impl Default for GitLabSource {
+    /// [DEFAULT_GITLAB_API_URL], authenticated with `GITLAB_TOKEN` if set.
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_GITLAB_API_URL.to_string(),
+            token: std::env::var(GITLAB_TOKEN_ENV)
+                .ok()
+                .filter(|x| !x.is_empty()),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl GitLabSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Base URL of the API, e.g. `https://gitlab.example.org/api/v4`.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Token sent as `PRIVATE-TOKEN`. Defaults to `GITLAB_TOKEN`.
+    pub fn token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GlResponse {
+    id: String,
+}
+
+#[async_trait]
+impl RevisionSource for GitLabSource {
+    async fn resolve(&self, repository: &Repository, branch: &str) -> Result<String> {
+        if repository.host != GitHost::GitLab {
+            return Err(unsupported("GitLab", repository));
+        }
+        let client = client(self.timeout)?;
+        let mut request = client.get(format!(
+            "{}/projects/{}%2F{}/repository/commits/{}",
+            self.base_url, repository.owner, repository.repo, branch
+        ));
+        if let Some(token) = &self.token {
+            request = request.header("PRIVATE-TOKEN", token);
+        }
+        let response = get(&client, request).await?;
+        if !response.status().is_success() {
+            return Err(Error::Revision(format!(
+                "GitLab could not resolve {} of {}: {}",
+                branch,
+                repository,
+                response.status()
+            )));
+        }
+        Ok(response.json::<GlResponse>().await?.id)
+    }
+
+    fn describe(&self, repository: &Repository, branch: &str) -> String {
+        format!("{}/{}@{}", repository, branch, self.base_url)
+    }
+}
+
+/// Reads the `git-revision` file the NixOS channel server publishes for every channel.
+/// Only resolves branches of `NixOS/nixpkgs`.
+#[derive(Debug, Clone)]
+pub struct ChannelSource {
+    base_url: String,
+    timeout: std::time::Duration,
+}
+
+impl Default for ChannelSource {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_CHANNELS_URL.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl ChannelSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl RevisionSource for ChannelSource {
+    async fn resolve(&self, repository: &Repository, branch: &str) -> Result<String> {
+        if *repository != Repository::nixpkgs() {
+            return Err(unsupported("The channel server", repository));
+        }
+        let client = client(self.timeout)?;
+        let request = client.get(format!("{}/{}/git-revision", self.base_url, branch));
+        let response = get(&client, request).await?;
+        if !response.status().is_success() {
+            return Err(Error::Revision(format!(
+                "Channel {} could not be resolved: {}",
+                branch,
+                response.status()
+            )));
+        }
+        let rev = response.text().await?.trim().to_string();
+        if rev.is_empty() {
+            return Err(Error::Revision(format!(
+                "Channel {} has no revision",
+                branch
+            )));
+        }
+        Ok(rev)
+    }
+
+    fn describe(&self, _repository: &Repository, branch: &str) -> String {
+        format!("channel:{}/{}", self.base_url, branch)
+    }
+}
+
+/// Reads the revision an input is locked to in a `flake.lock`, whatever the repository
+/// and branch.
+#[derive(Debug, Clone)]
+pub struct FlakeLockSource {
+    path: String,
+    input: String,
+}
+
+impl FlakeLockSource {
+    /// Source reading the root input `input` (usually `nixpkgs`) of the lock file at `path`.
+    pub fn new(path: impl Into<String>, input: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            input: input.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl RevisionSource for FlakeLockSource {
+    async fn resolve(&self, _repository: &Repository, _branch: &str) -> Result<String> {
+        get_flake_lock_revision(&self.path, &self.input)
+    }
+
+    fn describe(&self, _repository: &Repository, _branch: &str) -> String {
+        format!("flake-lock:{}#{}", self.path, self.input)
+    }
+}
+
+/// Always resolves to the same revision, e.g. to pin libxinux to a known nixpkgs.
+#[derive(Debug, Clone)]
+pub struct FixedSource {
+    rev: String,
+}
+
+impl FixedSource {
+    pub fn new(rev: impl Into<String>) -> Self {
+        Self { rev: rev.into() }
+    }
+}
+
+#[async_trait]
+impl RevisionSource for FixedSource {
+    async fn resolve(&self, _repository: &Repository, _branch: &str) -> Result<String> {
+        Ok(self.rev.clone())
+    }
+
+    fn describe(&self, _repository: &Repository, _branch: &str) -> String {
+        format!("fixed:{}", self.rev)
+    }
+}
+
+/// Tries several sources in order until one succeeds.
+pub struct FallbackSource {
+    sources: Vec<Box<dyn RevisionSource>>,
+}
+
+impl FallbackSource {
+    pub fn new(sources: Vec<Box<dyn RevisionSource>>) -> Self {
+        Self { sources }
+    }
+}
+
+#[async_trait]
+impl RevisionSource for FallbackSource {
+    async fn resolve(&self, repository: &Repository, branch: &str) -> Result<String> {
+        let mut last_err = None;
+        for source in &self.sources {
+            match source.resolve(repository, branch).await {
+                Ok(rev) => return Ok(rev),
+                Err(err) => {
+                    debug!(
+                        "Could not resolve {}: {}",
+                        source.describe(repository, branch),
+                        err
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::Revision("No revision sources".to_string())))
+    }
+
+    fn describe(&self, repository: &Repository, branch: &str) -> String {
+        self.sources
+            .iter()
+            .map(|x| x.describe(repository, branch))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CachedRevision {
+    rev: String,
+    resolved_at: DateTime<Utc>,
+}
+
+/// Keeps the answers of another source in a file for a while.
+///
+/// A stale answer is still returned if the source fails, so that libxinux keeps
+/// working offline.
+pub struct CachedSource<S> {
+    inner: S,
+    path: String,
+    ttl: Duration,
+}
+
+impl<S: RevisionSource> CachedSource<S> {
+    /// Cache the answers of `inner` in the JSON file at `path` for `ttl`.
+    pub fn new(inner: S, path: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            inner,
+            path: path.into(),
+            ttl,
+        }
+    }
+
+    fn read(&self) -> HashMap<String, CachedRevision> {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or_default()
+    }
+
+    fn write(&self, key: String, rev: &str) -> Result<()> {
+        let mut cached = self.read();
+        cached.insert(
+            key,
+            CachedRevision {
+                rev: rev.to_string(),
+                resolved_at: Utc::now(),
+            },
+        );
+        if let Some(dir) = std::path::Path::new(&self.path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmppath = format!("{}.{}.tmp", self.path, std::process::id());
+        std::fs::write(&tmppath, serde_json::to_string_pretty(&cached)?)?;
+        std::fs::rename(&tmppath, &self.path)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: RevisionSource> RevisionSource for CachedSource<S> {
+    async fn resolve(&self, repository: &Repository, branch: &str) -> Result<String> {
+        let key = self.inner.describe(repository, branch);
+        let cached = self.read().remove(&key);
+        if let Some(cached) = &cached {
+            if Utc::now() - cached.resolved_at < self.ttl {
+                debug!("Using cached revision {} for {}", cached.rev, key);
+                return Ok(cached.rev.clone());
+            }
+        }
+        match self.inner.resolve(repository, branch).await {
+            Ok(rev) => {
+                if let Err(err) = self.write(key, &rev) {
+                    warn!("Could not cache revision: {}", err);
+                }
+                Ok(rev)
+            }
+            Err(err) => match cached {
+                Some(cached) => {
+                    warn!("Using outdated revision for {}: {}", key, err);
+                    Ok(cached.rev)
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    fn describe(&self, repository: &Repository, branch: &str) -> String {
+        self.inner.describe(repository, branch)
+    }
+}
+
+/// Source used when none is configured: the GitHub and GitLab APIs, then the NixOS
+/// channel server, cached in `{cache_dir}/revisions.json` for [DEFAULT_TTL].
+pub fn default_source(cache_dir: &str) -> CachedSource<FallbackSource> {
+    CachedSource::new(
+        FallbackSource::new(vec![
+            Box::new(GitHubSource::new()),
+            Box::new(GitLabSource::new()),
+            Box::new(ChannelSource::new()),
+        ]),
+        format!("{}/revisions.json", cache_dir),
+        DEFAULT_TTL,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Response, StandIn};
+
+    const REV_A: &str = "0123456789abcdef0123456789abcdef01234567";
+    const REV_B: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+    fn commit(rev: &str) -> Response {
+        Response::new(200, format!(r#"{{"sha": "{}"}}"#, rev))
+    }
+
+    fn fork() -> Repository {
+        "github:xinux-org/nixpkgs".parse().unwrap()
+    }
+
+    fn cache_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "libxinux-revisions-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn github_sends_token() {
+        let server = StandIn::start(vec![commit(REV_A)]).await;
+        let source = GitHubSource::new()
+            .base_url(&server.url)
+            .token(Some("secret".to_string()));
+        assert_eq!(
+            source.resolve(&fork(), "nixos-unstable").await.unwrap(),
+            REV_A
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("get /repos/xinux-org/nixpkgs/commits/nixos-unstable "));
+        assert!(requests[0].contains("authorization: bearer secret\r\n"));
+    }
+
+    #[tokio::test]
+    async fn github_retries_server_errors() {
+        let server = StandIn::start(vec![Response::new(502, ""), commit(REV_A)]).await;
+        let source = GitHubSource::new().base_url(&server.url).token(None);
+        assert_eq!(
+            source.resolve(&fork(), "nixos-unstable").await.unwrap(),
+            REV_A
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("authorization:"));
+    }
+
+    #[tokio::test]
+    async fn github_does_not_retry_client_errors() {
+        let server = StandIn::start(vec![Response::new(404, ""), commit(REV_A)]).await;
+        let source = GitHubSource::new().base_url(&server.url).token(None);
+        assert!(source.resolve(&fork(), "missing").await.is_err());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn channel_reads_git_revision() {
+        let server = StandIn::start(vec![Response::new(200, format!("{}\n", REV_A))]).await;
+        let source = ChannelSource::new().base_url(&server.url);
+        assert_eq!(
+            source
+                .resolve(&Repository::nixpkgs(), "nixos-24.05")
+                .await
+                .unwrap(),
+            REV_A
+        );
+        assert!(server.requests()[0].starts_with("get /nixos-24.05/git-revision "));
+
+        // The channel server only knows about NixOS/nixpkgs
+        assert!(source.resolve(&fork(), "nixos-24.05").await.is_err());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cached_within_ttl() {
+        let server = StandIn::start(vec![commit(REV_A), commit(REV_B)]).await;
+        let path = cache_path("ttl");
+        let github = || GitHubSource::new().base_url(&server.url).token(None);
+
+        let source = CachedSource::new(github(), &path, Duration::hours(1));
+        assert_eq!(source.resolve(&fork(), "main").await.unwrap(), REV_A);
+        assert_eq!(source.resolve(&fork(), "main").await.unwrap(), REV_A);
+        assert_eq!(server.requests().len(), 1);
+
+        // Once the answer has expired, the source is asked again
+        let source = CachedSource::new(github(), &path, Duration::zero());
+        assert_eq!(source.resolve(&fork(), "main").await.unwrap(), REV_B);
+        assert_eq!(server.requests().len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn cached_stale_on_failure() {
+        let server = StandIn::start(vec![commit(REV_A), Response::new(404, "")]).await;
+        let path = cache_path("stale");
+        let source = CachedSource::new(
+            GitHubSource::new().base_url(&server.url).token(None),
+            &path,
+            Duration::zero(),
+        );
+        assert_eq!(source.resolve(&fork(), "main").await.unwrap(), REV_A);
+        assert_eq!(source.resolve(&fork(), "main").await.unwrap(), REV_A);
+        assert_eq!(server.requests().len(), 2);
+
+        // Nothing cached for another branch
+        assert!(source.resolve(&fork(), "other").await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
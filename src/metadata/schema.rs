@@ -0,0 +1,139 @@
+//! # Database schema
+//!
+//! The metadata database records its layout in `PRAGMA user_version`. Databases
+//! published before the version was recorded report `0` and use the same layout as
+//! version 1.
+//!
+//! The rest of libxinux queries the `pkgs` and `meta` tables of version 1. Newer
+//! layouts are made to look the same by [prepare], which creates temporary views
+//! on the connection, so every query keeps working unchanged.
+
+use crate::{Error, Result};
+use rusqlite::Connection;
+
+/// Layout of a metadata database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SchemaVersion {
+    /// Separate `pkgs(attribute, pname, version)` and `meta(attribute, ...)` tables.
+    V1,
+    /// A single `packages` table holding both.
+    V2,
+}
+
+/// Newest schema version libxinux understands.
+pub const LATEST_VERSION: u32 = 2;
+
+/// Columns every package needs, grouped by the `pkgs` and `meta` tables of version 1.
+const PKGS_COLUMNS: &[&str] = &["attribute", "pname", "version"];
+const META_COLUMNS: &[&str] = &[
+    "attribute",
+    "description",
+    "long_description",
+    "broken",
+    "insecure",
+    "unfree",
+];
+
+impl SchemaVersion {
+    /// Schema version stored in `user_version`.
+    pub fn number(self) -> u32 {
+        match self {
+            SchemaVersion::V1 => 1,
+            SchemaVersion::V2 => 2,
+        }
+    }
+
+    fn from_number(version: u32) -> Result<Self> {
+        match version {
+            0 | 1 => Ok(SchemaVersion::V1),
+            2 => Ok(SchemaVersion::V2),
+            _ => Err(Error::IncompatibleDatabase {
+                version,
+                reason: format!(
+                    "only schema versions up to {} are supported, try updating libxinux",
+                    LATEST_VERSION
+                ),
+            }),
+        }
+    }
+
+    /// Tables of this schema and the columns they need to have.
+    fn required_columns(self) -> Vec<(&'static str, Vec<&'static str>)> {
+        match self {
+            SchemaVersion::V1 => vec![
+                ("pkgs", PKGS_COLUMNS.to_vec()),
+                ("meta", META_COLUMNS.to_vec()),
+            ],
+            SchemaVersion::V2 => {
+                let mut columns = PKGS_COLUMNS.to_vec();
+                columns.extend(META_COLUMNS.iter().filter(|c| !PKGS_COLUMNS.contains(c)));
+                vec![("packages", columns)]
+            }
+        }
+    }
+}
+
+/// Names of the columns of `table`, empty if it does not exist.
+pub fn columns(db: &Connection, table: &str) -> Result<Vec<String>> {
+    let mut stmt = db.prepare("SELECT name FROM pragma_table_info(?)")?;
+    let columns = stmt
+        .query_map([table], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(columns)
+}
+
+/// Whether `table` has a column called `column`, for data only some databases provide.
+pub fn has_column(db: &Connection, table: &str, column: &str) -> Result<bool> {
+    Ok(columns(db, table)?.iter().any(|c| c == column))
+}
+
+/// Read the schema version of `db` and check that it has every required table and column.
+pub fn check(db: &Connection) -> Result<SchemaVersion> {
+    let version: u32 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let schema = SchemaVersion::from_number(version)?;
+    for (table, required) in schema.required_columns() {
+        let present = columns(db, table)?;
+        if present.is_empty() {
+            return Err(Error::IncompatibleDatabase {
+                version,
+                reason: format!("table {} is missing", table),
+            });
+        }
+        let missing = required
+            .iter()
+            .filter(|c| !present.iter().any(|p| p == *c))
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(Error::IncompatibleDatabase {
+                version,
+                reason: format!("table {} lacks columns {}", table, missing.join(", ")),
+            });
+        }
+    }
+    Ok(schema)
+}
+
+/// [check] `db` and make it queryable through the `pkgs` and `meta` tables of version 1.
+///
+/// Has to be called on every new connection, the views it creates are temporary.
+pub fn prepare(db: &Connection) -> Result<SchemaVersion> {
+    let schema = check(db)?;
+    match schema {
+        SchemaVersion::V1 => {}
+        SchemaVersion::V2 => {
+            db.execute_batch(
+                "CREATE TEMP VIEW IF NOT EXISTS pkgs AS SELECT attribute, pname, version FROM packages;
+                 CREATE TEMP VIEW IF NOT EXISTS meta AS SELECT * FROM packages;",
+            )?;
+        }
+    }
+    Ok(schema)
+}
+
+/// Open the database at `path` and [prepare] it.
+pub fn open(path: &str) -> Result<Connection> {
+    let db = Connection::open(path)?;
+    prepare(&db)?;
+    Ok(db)
+}
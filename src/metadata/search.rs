@@ -1,19 +1,43 @@
 #![allow(clippy::needless_lifetimes)]
 
-use anyhow::Result;
-use log::debug;
-use serde::{Deserialize, Deserializer};
+use crate::{
+    metadata::{
+        programs::{programs_by_attribute, programs_database},
+        query, schema as db_schema,
+    },
+    Context, Result,
+};
+use log::{debug, warn};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
+use std::{
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+};
 use tantivy::{
-    query::QueryParser,
-    schema::{IndexRecordOption, Schema, TextFieldIndexing, TextOptions, STORED},
-    Document, Index, Searcher, TantivyDocument,
+    query::{
+        AllQuery, BooleanQuery, BoostQuery, ConstScoreQuery, ExistsQuery, Occur, Query,
+        QueryParser, RangeQuery, TermQuery,
+    },
+    schema::{
+        Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Type, FAST, INDEXED,
+        STORED, STRING,
+    },
+    Document, Index, IndexWriter, Searcher, TantivyDocument, Term,
 };
 
+/// Version of the index layout, stored with on-disk indexes so that indexes built by
+/// an older layout are rebuilt. Bump whenever [build_schema] or what is indexed changes.
+const INDEX_VERSION: u32 = 3;
+
 pub struct DbSearcher {
     searcher: Searcher,
     schema: Schema,
     query_parser: QueryParser,
+    fields: Fields,
+    /// Platform of the [Context] the searcher was created with.
+    arch: String,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -94,10 +118,10 @@ where
     match value {
         Value::Array(mut arr) => {
             if arr.len() == 1 {
-                if let Value::String(b) = arr.remove(0) {
-                    Ok(b == "1")
-                } else {
-                    Ok(false)
+                match arr.remove(0) {
+                    Value::String(b) => Ok(b == "1"),
+                    Value::Number(n) => Ok(n.as_u64().is_some_and(|x| x != 0)),
+                    _ => Ok(false),
                 }
             } else {
                 Err(serde::de::Error::custom(
@@ -109,11 +133,36 @@ where
     }
 }
 
+/// Platform packages have to be available on to be found.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlatformFilter<'a> {
+    /// The platform of the [Context] the searcher was created with.
+    #[default]
+    Current,
+    /// Any platform.
+    Any,
+    /// A Nix system type such as `aarch64-darwin`.
+    Platform(&'a str),
+}
+
 #[derive(Debug)]
 pub struct SearchQuery<'a> {
     pub query: &'a str,
     pub limit: usize,
     pub score_threshold: f32,
+    /// Leave out packages marked broken.
+    pub exclude_broken: bool,
+    /// Leave out packages with known vulnerabilities.
+    pub exclude_insecure: bool,
+    /// Leave out packages with an unfree license.
+    pub exclude_unfree: bool,
+    /// Only find packages with this license, e.g. `MIT` or `GPL-3.0-or-later`.
+    pub license: Option<&'a str>,
+    /// Only find packages available on this platform. Packages that do not list their
+    /// platforms are always found.
+    pub platform: PlatformFilter<'a>,
+    /// Only find attributes in this namespace, e.g. `python3Packages.`.
+    pub attribute_prefix: Option<&'a str>,
 }
 
 impl<'a> Default for SearchQuery<'a> {
@@ -122,40 +171,270 @@ impl<'a> Default for SearchQuery<'a> {
             query: "",
             limit: 10,
             score_threshold: 10.0,
+            exclude_broken: false,
+            exclude_insecure: false,
+            exclude_unfree: false,
+            license: None,
+            platform: PlatformFilter::default(),
+            attribute_prefix: None,
         }
     }
 }
 
-pub fn get_searcher(db: &rusqlite::Connection) -> Result<DbSearcher> {
+/// Fields of the search index.
+struct Fields {
+    attribute: Field,
+    /// The attribute as a single term, for namespace filters.
+    attribute_path: Field,
+    version: Field,
+    pname: Field,
+    description: Field,
+    long_description: Field,
+    broken: Field,
+    insecure: Field,
+    unfree: Field,
+    program: Field,
+    license: Field,
+    platform: Field,
+}
+
+impl Fields {
+    fn new(schema: &Schema) -> Result<Self> {
+        Ok(Self {
+            attribute: schema.get_field("attribute")?,
+            attribute_path: schema.get_field("attributepath")?,
+            version: schema.get_field("version")?,
+            pname: schema.get_field("pname")?,
+            description: schema.get_field("description")?,
+            long_description: schema.get_field("longdescription")?,
+            broken: schema.get_field("broken")?,
+            insecure: schema.get_field("insecure")?,
+            unfree: schema.get_field("unfree")?,
+            program: schema.get_field("program")?,
+            license: schema.get_field("license")?,
+            platform: schema.get_field("platform")?,
+        })
+    }
+}
+
+fn build_schema() -> Schema {
     let text_field_indexing = TextFieldIndexing::default()
         .set_tokenizer("ngram3")
         .set_index_option(IndexRecordOption::WithFreqsAndPositions);
     let text_options = TextOptions::default().set_indexing_options(text_field_indexing);
 
-    // Create a Tantivy schema
     let mut schema_builder = Schema::builder();
-    let attribute = schema_builder.add_text_field("attribute", text_options.clone() | STORED);
-    let version = schema_builder.add_text_field("version", STORED);
-    let pname = schema_builder.add_text_field("pname", text_options.clone() | STORED);
-    let description = schema_builder.add_text_field("description", text_options.clone() | STORED);
-    let long_description = schema_builder.add_text_field("longdescription", text_options);
-    let broken = schema_builder.add_u64_field("broken", STORED);
-    let insecure = schema_builder.add_u64_field("insecure", STORED);
-    let unfree = schema_builder.add_u64_field("unfree", STORED);
-
-    let schema = schema_builder.build();
-
-    // Create an index in a temporary directory
-    let index = Index::create_in_ram(schema.clone());
+    schema_builder.add_text_field("attribute", text_options.clone() | STORED);
+    schema_builder.add_text_field("attributepath", STRING);
+    schema_builder.add_text_field("version", STORED);
+    schema_builder.add_text_field("pname", text_options.clone() | STORED);
+    schema_builder.add_text_field("description", text_options.clone() | STORED);
+    schema_builder.add_text_field("longdescription", text_options);
+    schema_builder.add_u64_field("broken", INDEXED | FAST | STORED);
+    schema_builder.add_u64_field("insecure", INDEXED | FAST | STORED);
+    schema_builder.add_u64_field("unfree", INDEXED | FAST | STORED);
+    schema_builder.add_text_field("program", STRING);
+    schema_builder.add_text_field("license", STRING);
+    schema_builder.add_text_field("platform", STRING | FAST);
+    schema_builder.build()
+}
+
+/// Tokenizers are not persisted with the index, so they are registered on every open.
+fn register_tokenizers(index: &Index) -> Result<()> {
     index.tokenizers().register(
         "ngram3",
         tantivy::tokenizer::NgramTokenizer::new(3, 3, false)?,
     );
+    Ok(())
+}
+
+/// Identifies the databases an on-disk index was built from, stored in the index
+/// directory as [STAMP_FILE].
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct IndexStamp {
+    /// [INDEX_VERSION] of the index, missing in indexes older than the field.
+    #[serde(default)]
+    version: u32,
+    database_size: u64,
+    database_modified: u64,
+    /// The `programs.sqlite` the commands were indexed from, if there was one.
+    #[serde(default)]
+    programs: Option<ProgramsStamp>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ProgramsStamp {
+    path: String,
+    size: u64,
+    modified: u64,
+}
 
-    let mut index_writer = index.writer(50_000_000)?;
+const STAMP_FILE: &str = "libxinux.json";
+
+impl IndexStamp {
+    fn of_databases(ctx: &Context, path: &str) -> Result<Self> {
+        let (database_size, database_modified) = size_and_modified(path)?;
+        let programs = match programs_database(ctx) {
+            Some(path) => {
+                let (size, modified) = size_and_modified(&path)?;
+                Some(ProgramsStamp {
+                    path,
+                    size,
+                    modified,
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            version: INDEX_VERSION,
+            database_size,
+            database_modified,
+            programs,
+        })
+    }
+
+    fn read(index_dir: &Path) -> Option<Self> {
+        serde_json::from_slice(&fs::read(index_dir.join(STAMP_FILE)).ok()?).ok()
+    }
+}
+
+/// Size of the file at `path` and its modification time in seconds since the epoch.
+fn size_and_modified(path: &str) -> Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let modified = meta
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    Ok((meta.len(), modified))
+}
+
+/// Path of the search index of the database at `db_path`: `{rev}.index` next to `{rev}.db`.
+pub fn index_path(db_path: &str) -> Option<PathBuf> {
+    Some(Path::new(db_path.strip_suffix(".db")?).with_extension("index"))
+}
+
+/// Index the packages in `db` for searching.
+///
+/// Besides names and descriptions, the commands each package provides are indexed, so
+/// that searching for a command like `rg` finds the package providing it.
+///
+/// For a database in the cache, the index is kept on disk next to it and reused as long
+/// as neither the database nor the `programs.sqlite` of the channel changes. Other databases, and cached ones whose index cannot be
+/// written, are indexed in memory.
+///
+/// [PlatformFilter::Current] searches for packages available on the platform of `ctx`.
+pub fn get_searcher(ctx: &Context, db: &rusqlite::Connection) -> Result<DbSearcher> {
+    let cached = db
+        .path()
+        .filter(|x| is_cached(ctx, x))
+        .and_then(|x| Some((x, index_path(x)?)));
+    if let Some((db_path, index_dir)) = cached {
+        match open_or_create_index(ctx, db, db_path, &index_dir) {
+            Ok(index) => return searcher(ctx, &index),
+            Err(err) => warn!(
+                "Indexing in memory, as {} is unusable: {}",
+                index_dir.display(),
+                err
+            ),
+        }
+    }
+    let index = Index::create_in_ram(build_schema());
+    register_tokenizers(&index)?;
+    fill_index(ctx, db, &index)?;
+    searcher(ctx, &index)
+}
+
+/// Whether the database at `db_path` is in the cache directory of `ctx`.
+fn is_cached(ctx: &Context, db_path: &str) -> bool {
+    let parent = Path::new(db_path)
+        .parent()
+        .and_then(|x| fs::canonicalize(x).ok());
+    parent.is_some() && parent == fs::canonicalize(ctx.cache_dir()).ok()
+}
+
+fn open_or_create_index(
+    ctx: &Context,
+    db: &rusqlite::Connection,
+    db_path: &str,
+    index_dir: &Path,
+) -> Result<Index> {
+    let stamp = IndexStamp::of_databases(ctx, db_path)?;
+    if IndexStamp::read(index_dir).as_ref() == Some(&stamp) {
+        match open_index(index_dir) {
+            Ok(index) => return Ok(index),
+            Err(err) => debug!("Rebuilding search index {}: {}", index_dir.display(), err),
+        }
+    }
+
+    debug!("Building search index {}", index_dir.display());
+    let tmp_dir = PathBuf::from(format!(
+        "{}.{}.tmp",
+        index_dir.display(),
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&tmp_dir);
+    fs::create_dir_all(&tmp_dir)?;
+    let built = (|| {
+        let index = Index::create_in_dir(&tmp_dir, build_schema())?;
+        register_tokenizers(&index)?;
+        fill_index(ctx, db, &index)?;
+        fs::write(tmp_dir.join(STAMP_FILE), serde_json::to_vec(&stamp)?)?;
+        Ok(())
+    })();
+    if let Err(err) = built {
+        let _ = fs::remove_dir_all(&tmp_dir);
+        return Err(err);
+    }
+    let _ = fs::remove_dir_all(index_dir);
+    if let Err(err) = fs::rename(&tmp_dir, index_dir) {
+        // Another process may have just put its index in place
+        debug!("Could not move search index into place: {}", err);
+        let _ = fs::remove_dir_all(&tmp_dir);
+    }
+    open_index(index_dir)
+}
+
+fn open_index(index_dir: &Path) -> Result<Index> {
+    let index = Index::open_in_dir(index_dir)?;
+    Fields::new(&index.schema())?;
+    register_tokenizers(&index)?;
+    Ok(index)
+}
+
+fn fill_index(ctx: &Context, db: &rusqlite::Connection, index: &Index) -> Result<()> {
+    let Fields {
+        attribute,
+        attribute_path,
+        version,
+        pname,
+        description,
+        long_description,
+        broken,
+        insecure,
+        unfree,
+        program,
+        license,
+        platform,
+    } = Fields::new(&index.schema())?;
+
+    let mut index_writer: IndexWriter = index.writer(50_000_000)?;
+
+    let programs = programs_by_attribute(ctx, db)?;
 
     // Query to select data from SQLite
-    let mut stmt = db.prepare("SELECT pkgs.attribute, pkgs.version, pkgs.pname, meta.description, meta.long_description, meta.broken, meta.insecure, meta.unfree FROM pkgs JOIN meta ON pkgs.attribute = meta.attribute")?;
+    let optional = |column: &str| -> Result<String> {
+        Ok(if db_schema::has_column(db, "meta", column)? {
+            format!("meta.{}", column)
+        } else {
+            "NULL".to_string()
+        })
+    };
+    let mut stmt = db.prepare(&format!(
+        "SELECT pkgs.attribute, pkgs.version, pkgs.pname, meta.description, meta.long_description, meta.broken, meta.insecure, meta.unfree, {}, {} FROM pkgs JOIN meta ON pkgs.attribute = meta.attribute",
+        optional("license")?,
+        optional("platforms")?
+    ))?;
     let meta_iter = stmt.query_map([], |row| {
         Ok((
             row.get::<_, String>(0)?,
@@ -163,17 +442,23 @@ pub fn get_searcher(db: &rusqlite::Connection) -> Result<DbSearcher> {
             row.get::<_, Option<String>>(2),
             row.get::<_, Option<String>>(3),
             row.get::<_, Option<String>>(4),
-            row.get::<_, Option<u64>>(5),
-            row.get::<_, Option<u64>>(6),
-            row.get::<_, Option<u64>>(7),
+            row.get::<_, Option<bool>>(5),
+            row.get::<_, Option<bool>>(6),
+            row.get::<_, Option<bool>>(7),
+            row.get::<_, Option<String>>(8),
+            row.get::<_, Option<String>>(9),
         ))
     })?;
 
     // Add documents to the index
     for meta in meta_iter {
-        let (attr, ver, pnm, desc, long_desc, brk, insec, unfr) = meta?;
+        let (attr, ver, pnm, desc, long_desc, brk, insec, unfr, licenses, platforms) = meta?;
         let mut doc = TantivyDocument::default();
+        for name in programs.get(&attr).into_iter().flatten() {
+            doc.add_text(program, name);
+        }
         doc.add_text(attribute, &attr);
+        doc.add_text(attribute_path, &attr);
         if let Ok(Some(v)) = ver {
             doc.add_text(version, &v);
         }
@@ -186,40 +471,135 @@ pub fn get_searcher(db: &rusqlite::Connection) -> Result<DbSearcher> {
         if let Ok(Some(ld)) = long_desc {
             doc.add_text(long_description, &ld);
         }
-        if let Ok(Some(b)) = brk {
-            doc.add_text(broken, b);
-        }
-        if let Ok(Some(i)) = insec {
-            doc.add_text(insecure, i);
+        doc.add_u64(broken, brk.ok().flatten().unwrap_or(false).into());
+        doc.add_u64(insecure, insec.ok().flatten().unwrap_or(false).into());
+        doc.add_u64(unfree, unfr.ok().flatten().unwrap_or(false).into());
+        for name in query::list(licenses.ok().flatten()) {
+            doc.add_text(license, name);
         }
-        if let Ok(Some(u)) = unfr {
-            doc.add_text(unfree, u);
+        for name in query::list(platforms.ok().flatten()) {
+            doc.add_text(platform, name);
         }
         index_writer.add_document(doc)?;
     }
     index_writer.commit()?;
+    Ok(())
+}
 
-    // Search in the index
+fn searcher(ctx: &Context, index: &Index) -> Result<DbSearcher> {
+    let schema = index.schema();
+    let fields = Fields::new(&schema)?;
     let reader = index.reader()?;
     let searcher = reader.searcher();
-    let mut query_parser =
-        QueryParser::for_index(&index, vec![attribute, description, long_description]);
-    query_parser.set_field_boost(attribute, 100.0);
+    let mut query_parser = QueryParser::for_index(
+        index,
+        vec![
+            fields.attribute,
+            fields.description,
+            fields.long_description,
+        ],
+    );
+    query_parser.set_field_boost(fields.attribute, 100.0);
     Ok(DbSearcher {
         searcher,
         schema,
         query_parser,
+        fields,
+        arch: ctx.arch().to_string(),
     })
 }
 
+/// Restrictions of `sq` on the packages found, as queries that do not affect scores.
+fn filters(sq: &SearchQuery, fields: &Fields, arch: &str) -> Vec<(Occur, Box<dyn Query>)> {
+    let term = |term: Term| -> Box<dyn Query> {
+        Box::new(ConstScoreQuery::new(
+            Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+            0.0,
+        ))
+    };
+    let mut filters = vec![];
+    for (excluded, field) in [
+        (sq.exclude_broken, fields.broken),
+        (sq.exclude_insecure, fields.insecure),
+        (sq.exclude_unfree, fields.unfree),
+    ] {
+        if excluded {
+            filters.push((Occur::MustNot, term(Term::from_field_u64(field, 1))));
+        }
+    }
+    if let Some(license) = sq.license {
+        filters.push((
+            Occur::Must,
+            term(Term::from_field_text(fields.license, license)),
+        ));
+    }
+    let platform = match sq.platform {
+        PlatformFilter::Current => Some(arch),
+        PlatformFilter::Any => None,
+        PlatformFilter::Platform(platform) => Some(platform),
+    };
+    if let Some(platform) = platform {
+        // Packages without platforms are assumed to be available everywhere
+        let unlisted = BooleanQuery::new(vec![
+            (Occur::Must, Box::new(AllQuery) as Box<dyn Query>),
+            (
+                Occur::MustNot,
+                Box::new(ExistsQuery::new_exists_query("platform".to_string())),
+            ),
+        ]);
+        filters.push((
+            Occur::Must,
+            Box::new(BooleanQuery::union(vec![
+                term(Term::from_field_text(fields.platform, platform)),
+                Box::new(ConstScoreQuery::new(Box::new(unlisted), 0.0)),
+            ])),
+        ));
+    }
+    if let Some(prefix) = sq.attribute_prefix.filter(|x| !x.is_empty()) {
+        let range = RangeQuery::new_term_bounds(
+            "attributepath".to_string(),
+            Type::Str,
+            &Bound::Included(Term::from_field_text(fields.attribute_path, prefix)),
+            &Bound::Excluded(Term::from_field_text(
+                fields.attribute_path,
+                &format!("{}{}", prefix, char::MAX),
+            )),
+        );
+        filters.push((
+            Occur::Must,
+            Box::new(ConstScoreQuery::new(Box::new(range), 0.0)),
+        ));
+    }
+    filters
+}
+
 pub fn search(sq: &SearchQuery, dbsearcher: &DbSearcher) -> Result<Vec<SearchResult>> {
     let DbSearcher {
         searcher,
         schema,
         query_parser,
+        fields,
+        arch,
     } = dbsearcher;
 
-    let (query, _) = query_parser.parse_query_lenient(sq.query.trim());
+    let (mut query, _) = query_parser.parse_query_lenient(sq.query.trim());
+    // A single word may be the name of a command, e.g. `rg` for ripgrep
+    if !sq.query.trim().is_empty() && !sq.query.trim().contains(char::is_whitespace) {
+        let program_query = TermQuery::new(
+            Term::from_field_text(fields.program, sq.query.trim()),
+            IndexRecordOption::Basic,
+        );
+        query = Box::new(BooleanQuery::union(vec![
+            query,
+            Box::new(BoostQuery::new(Box::new(program_query), 100.0)),
+        ]));
+    }
+    let filters = filters(sq, fields, arch);
+    if !filters.is_empty() {
+        let mut clauses = vec![(Occur::Must, query)];
+        clauses.extend(filters);
+        query = Box::new(BooleanQuery::new(clauses));
+    }
     let top_docs: Vec<(f32, tantivy::DocAddress)> =
         searcher.search(&query, &tantivy::collector::TopDocs::with_limit(sq.limit))?;
 
@@ -239,3 +619,64 @@ pub fn search(sq: &SearchQuery, dbsearcher: &DbSearcher) -> Result<Vec<SearchRes
     }
     Ok(results)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::FakeRunner;
+    use std::sync::Arc;
+
+    fn context(name: &str) -> Context {
+        let dir =
+            std::env::temp_dir().join(format!("libxinux-search-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy();
+        Context::builder()
+            .runner(Arc::new(FakeRunner::new()))
+            .arch("x86_64-linux")
+            .home(format!("{}/home", dir))
+            .cache_dir(format!("{}/cache", dir))
+            .config_dir(format!("{}/config", dir))
+            .is_nixos(false)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn rebuilds_index_when_programs_database_appears() {
+        let ctx = context("programs");
+        fs::create_dir_all(ctx.cache_dir()).unwrap();
+        let db_path = format!("{}/abc.db", ctx.cache_dir());
+        rusqlite::Connection::open(&db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE pkgs (attribute TEXT, pname TEXT, version TEXT);
+                 CREATE TABLE meta (attribute TEXT, description TEXT, long_description TEXT,
+                     broken INTEGER, insecure INTEGER, unfree INTEGER);
+                 INSERT INTO pkgs VALUES ('ripgrep', 'ripgrep', '14.1.0');
+                 INSERT INTO meta VALUES ('ripgrep', 'Fast grep', NULL, 0, 0, 0);",
+            )
+            .unwrap();
+        let db = db_schema::open(&db_path).unwrap();
+        let index_dir = index_path(&db_path).unwrap();
+
+        get_searcher(&ctx, &db).unwrap();
+        assert_eq!(IndexStamp::read(&index_dir).unwrap().programs, None);
+
+        let channel = format!("{}/.nix-defexpr/channels/nixos", ctx.home().unwrap());
+        fs::create_dir_all(&channel).unwrap();
+        let programs_path = format!("{}/programs.sqlite", channel);
+        rusqlite::Connection::open(&programs_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE Programs (name TEXT, system TEXT, package TEXT);
+                 INSERT INTO Programs VALUES ('rg', 'x86_64-linux', 'ripgrep');",
+            )
+            .unwrap();
+
+        get_searcher(&ctx, &db).unwrap();
+        let programs = IndexStamp::read(&index_dir).unwrap().programs.unwrap();
+        assert_eq!(programs.path, programs_path);
+    }
+}
@@ -1,14 +1,20 @@
 use super::{get_channel, list::list};
-use crate::PackageAttr;
-use anyhow::{anyhow, Result};
-use tokio::process::Command;
+use crate::{
+    command::{CancellationToken, CommandSpec},
+    Context, Error, PackageAttr, Result,
+};
 
-pub async fn install(pkgs: &[&str], db: &rusqlite::Connection) -> Result<()> {
-    let installed = list(db).await?;
+pub async fn install(
+    ctx: &Context,
+    pkgs: &[&str],
+    db: &rusqlite::Connection,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let installed = list(ctx, db).await?;
     let mut pkgs_to_install = Vec::new();
     for pkg in pkgs {
         if installed.iter().any(|x| match x.attr {
-            PackageAttr::NixPkgs { ref attr } => attr == pkg,
+            PackageAttr::NixPkgs { ref attr, .. } => attr == pkg,
             _ => false,
         }) {
             println!("Package {} is already installed", pkg);
@@ -18,19 +24,18 @@ pub async fn install(pkgs: &[&str], db: &rusqlite::Connection) -> Result<()> {
     }
 
     if pkgs_to_install.is_empty() {
-        return Err(anyhow!("No new packages to install"));
+        return Err(Error::NothingToDo("No new packages to install".to_string()));
     }
 
-    let channel = get_channel()?;
-    let status = Command::new("nix-env")
-        .arg("-iA")
-        .args(pkgs_to_install.iter().map(|x| format!("{}.{}", channel, x)))
-        .status()
+    let channel = get_channel(ctx)?;
+    let output = ctx
+        .runner()
+        .run(
+            &CommandSpec::new("nix-env")
+                .arg("-iA")
+                .args(pkgs_to_install.iter().map(|x| format!("{}.{}", channel, x)))
+                .cancel_on(cancel),
+        )
         .await?;
-
-    if !status.success() {
-        Err(anyhow!("Failed to install packages"))
-    } else {
-        Ok(())
-    }
+    Error::check_output("nix-env", &output)
 }
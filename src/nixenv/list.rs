@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 
-use anyhow::Result;
 use serde::Deserialize;
 
 use crate::{
+    command::CommandSpec,
+    metadata::query,
     utils::{misc::get_pname_from_storepath, storedb::get_storebatch},
-    Package, PackageAttr,
+    Context, Package, PackageAttr, Result,
 };
 
 #[derive(Debug, Deserialize, Clone)]
@@ -19,64 +20,72 @@ struct EnvOutput {
     out: String,
 }
 
-pub async fn list(db: &rusqlite::Connection) -> Result<Vec<Package>> {
-    let output = std::process::Command::new("nix-env")
-        .arg("-q")
-        .arg("--out-path")
-        .arg("--installed")
-        .arg("--json")
-        .output()?;
-    let stdout = String::from_utf8(output.stdout)?;
+pub async fn list(ctx: &Context, db: &rusqlite::Connection) -> Result<Vec<Package>> {
+    let output = ctx
+        .runner()
+        .run(
+            &CommandSpec::new("nix-env")
+                .arg("-q")
+                .arg("--out-path")
+                .arg("--installed")
+                .arg("--json"),
+        )
+        .await?;
+    let stdout = output.stdout_string()?;
     let packages: HashMap<String, EnvPackage> = serde_json::from_str(&stdout)?;
 
-    let mut stmt = db.prepare("SELECT attribute, version FROM pkgs WHERE pname = ?")?;
-
     let mut pkgs = Vec::new();
 
     for (_name, pkg) in packages {
-        let mut rows = stmt.query([&pkg.pname])?;
-        let push;
-        if let Ok(Some(row)) = rows.next() {
-            push = Some(Package {
-                attr: PackageAttr::NixPkgs { attr: row.get(0)? },
-                version: row.get(1)?,
+        // Only packages whose attribute can be told from the name
+        if let [info] = &query::packages_by_pname(db, &pkg.pname)?[..] {
+            pkgs.push(Package {
+                attr: PackageAttr::NixPkgs {
+                    attr: info.attribute.clone(),
+                    outputs: None,
+                },
+                version: Some(info.version.clone()).filter(|x| !x.is_empty()),
                 pname: Some(pkg.pname),
                 ..Default::default()
             });
-        } else {
-            continue;
-        }
-        if let Ok(None) = rows.next() {
-            if let Some(push) = push {
-                pkgs.push(push);
-            }
         }
     }
 
     Ok(pkgs)
 }
 
-pub async fn list_accurate() -> Result<Vec<Package>> {
-    let output = std::process::Command::new("nix-env")
-        .arg("-q")
-        .arg("--out-path")
-        .arg("--installed")
-        .arg("--json")
-        .output()?;
-    let stdout = String::from_utf8(output.stdout)?;
+pub async fn list_accurate(ctx: &Context) -> Result<Vec<Package>> {
+    let output = ctx
+        .runner()
+        .run(
+            &CommandSpec::new("nix-env")
+                .arg("-q")
+                .arg("--out-path")
+                .arg("--installed")
+                .arg("--json"),
+        )
+        .await?;
+    let stdout = output.stdout_string()?;
     let packages: HashMap<String, EnvPackage> = serde_json::from_str(&stdout)?;
     let paths = packages
         .values()
-        .map(|x| x.outputs.out.split('/').last().unwrap_or(&x.outputs.out))
+        .map(|x| {
+            x.outputs
+                .out
+                .split('/')
+                .next_back()
+                .unwrap_or(&x.outputs.out)
+        })
         .collect::<Vec<_>>();
 
-    let storebatch = get_storebatch(paths.iter().map(AsRef::as_ref).collect()).await?;
+    let storebatch = get_storebatch(ctx, paths.iter().map(AsRef::as_ref).collect()).await?;
     Ok(storebatch
         .packages
         .into_iter()
         .map(|x| Package {
             attr: PackageAttr::NixPkgs {
                 attr: x.attribute.join("."),
+                outputs: None,
             },
             version: x.version.clone(),
             pname: get_pname_from_storepath(x.store.as_str(), x.version).ok(),
@@ -3,18 +3,61 @@ pub mod list;
 pub mod remove;
 pub mod update;
 
-use anyhow::{Context, Result};
-use std::process::Command;
+use crate::{
+    backend::PackageBackend, command::CommandSpec, Context, Error, Package, PackageUpdate, Result,
+};
+use async_trait::async_trait;
 
-pub fn get_channel() -> Result<String> {
-    let output = Command::new("nix-channel").arg("--list").output()?;
-    let output = String::from_utf8(output.stdout)?;
+/// [PackageBackend] using the `nix-env` command.
+pub struct NixEnvBackend<'a> {
+    ctx: &'a Context,
+    db: rusqlite::Connection,
+}
+
+impl<'a> NixEnvBackend<'a> {
+    pub fn new(ctx: &'a Context, db: rusqlite::Connection) -> Self {
+        Self { ctx, db }
+    }
+}
+
+#[async_trait(?Send)]
+impl PackageBackend for NixEnvBackend<'_> {
+    async fn list(&self) -> Result<Vec<Package>> {
+        list::list(self.ctx, &self.db).await
+    }
+
+    async fn install(&self, pkgs: &[&str]) -> Result<()> {
+        install::install(self.ctx, pkgs, &self.db, None).await
+    }
+
+    async fn remove(&self, pkgs: &[&str]) -> Result<()> {
+        remove::remove(self.ctx, pkgs, &self.db, None).await
+    }
+
+    async fn updatable(&self) -> Result<Vec<PackageUpdate>> {
+        update::updatable(self.ctx, &self.db).await
+    }
+
+    async fn update(&self, pkgs: &[&str]) -> Result<()> {
+        if pkgs.is_empty() {
+            update::update_all(self.ctx, None).await
+        } else {
+            update::update(self.ctx, pkgs, &self.db, None).await
+        }
+    }
+}
+
+pub fn get_channel(ctx: &Context) -> Result<String> {
+    let output = ctx
+        .runner()
+        .run_blocking(&CommandSpec::new("nix-channel").arg("--list"))?
+        .stdout_string()?;
     let channel = output
         .split("\n")
         .collect::<Vec<_>>()
         .iter()
         .find(|x| x.starts_with("nixos") || x.starts_with("nixpkgs"))
-        .context("Failed to get channel")?
+        .ok_or_else(|| Error::Config("Failed to get channel".to_string()))?
         .split_whitespace()
         .collect::<Vec<_>>()[0]
         .to_string();
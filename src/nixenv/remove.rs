@@ -1,16 +1,22 @@
 use super::list::list;
-use crate::PackageAttr;
-use anyhow::{anyhow, Result};
-use tokio::process::Command;
+use crate::{
+    command::{CancellationToken, CommandSpec},
+    Context, Error, PackageAttr, Result,
+};
 
-pub async fn remove(pkgs: &[&str], db: &rusqlite::Connection) -> Result<()> {
-    let installed = list(db).await?;
+pub async fn remove(
+    ctx: &Context,
+    pkgs: &[&str],
+    db: &rusqlite::Connection,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let installed = list(ctx, db).await?;
     let mut pkgs_to_remove = Vec::new();
     for pkg in pkgs {
         if let Some(Some(pname)) = installed
             .iter()
             .find(|x| match x.attr {
-                PackageAttr::NixPkgs { ref attr } => attr == pkg,
+                PackageAttr::NixPkgs { ref attr, .. } => attr == pkg,
                 _ => false,
             })
             .map(|x| x.pname.clone())
@@ -22,18 +28,17 @@ pub async fn remove(pkgs: &[&str], db: &rusqlite::Connection) -> Result<()> {
     }
 
     if pkgs_to_remove.is_empty() {
-        return Err(anyhow!("No packages to remove"));
+        return Err(Error::NothingToDo("No packages to remove".to_string()));
     }
 
-    let status = Command::new("nix-env")
-        .arg("--uninstall")
-        .args(pkgs_to_remove.iter())
-        .status()
+    let output = ctx
+        .runner()
+        .run(
+            &CommandSpec::new("nix-env")
+                .arg("--uninstall")
+                .args(pkgs_to_remove.iter())
+                .cancel_on(cancel),
+        )
         .await?;
-
-    if !status.success() {
-        Err(anyhow!("Failed to install packages"))
-    } else {
-        Ok(())
-    }
+    Error::check_output("nix-env", &output)
 }
@@ -1,18 +1,27 @@
 use super::get_channel;
-use crate::{nixenv::list::list, utils, PackageUpdate};
-use anyhow::{anyhow, Result};
-pub async fn updatable(db: &rusqlite::Connection) -> Result<Vec<PackageUpdate>> {
-    utils::misc::updatable(list(db).await?).await
+use crate::{
+    command::{CancellationToken, CommandSpec},
+    nixenv::list::list,
+    utils, Context, Error, PackageUpdate, Result,
+};
+
+pub async fn updatable(ctx: &Context, db: &rusqlite::Connection) -> Result<Vec<PackageUpdate>> {
+    utils::misc::updatable(ctx, list(ctx, db).await?).await
 }
 
-pub async fn update(pkgs: &[&str], db: &rusqlite::Connection) -> Result<()> {
-    let list = list(db)
+pub async fn update(
+    ctx: &Context,
+    pkgs: &[&str],
+    db: &rusqlite::Connection,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let list = list(ctx, db)
         .await?
         .into_iter()
         .map(|x| x.attr.to_string())
         .collect::<Vec<_>>();
     let mut pkgs_to_update = Vec::new();
-    let channel = get_channel()?;
+    let channel = get_channel(ctx)?;
     for pkg in pkgs {
         if list.contains(&pkg.to_string()) {
             pkgs_to_update.push(format!("{}.{}", channel, pkg));
@@ -22,18 +31,25 @@ pub async fn update(pkgs: &[&str], db: &rusqlite::Connection) -> Result<()> {
     }
 
     if pkgs_to_update.is_empty() {
-        return Err(anyhow!("No packages to update"));
+        return Err(Error::NothingToDo("No packages to update".to_string()));
     }
 
-    let status = tokio::process::Command::new("nix-env")
-        .arg("-uA")
-        .args(pkgs.iter())
-        .status()
+    let output = ctx
+        .runner()
+        .run(
+            &CommandSpec::new("nix-env")
+                .arg("-uA")
+                .args(pkgs_to_update.iter())
+                .cancel_on(cancel),
+        )
         .await?;
+    Error::check_output("nix-env", &output)
+}
 
-    if !status.success() {
-        Err(anyhow!("Failed to update packages"))
-    } else {
-        Ok(())
-    }
+pub async fn update_all(ctx: &Context, cancel: Option<&CancellationToken>) -> Result<()> {
+    let output = ctx
+        .runner()
+        .run(&CommandSpec::new("nix-env").arg("-u").cancel_on(cancel))
+        .await?;
+    Error::check_output("nix-env", &output)
 }
@@ -0,0 +1,146 @@
+use super::AuthMethod;
+use crate::{
+    command::{self, CancellationToken},
+    config::{configfile, plan::Plan},
+    progress::{self, ProgressSender},
+    Context, Error, Result,
+};
+use log::debug;
+use std::fs;
+
+/// Write the configuration of `plan` and switch to it.
+///
+/// Fails without changing anything if the configuration file was modified after the
+/// plan was made.
+pub async fn apply(
+    ctx: &Context,
+    plan: &Plan,
+    auth_method: AuthMethod<'_>,
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    if fs::read_to_string(&plan.path)? != plan.old_config {
+        return Err(Error::Config(format!(
+            "{} was modified after the changes were planned",
+            plan.path
+        )));
+    }
+
+    let config = configfile::get_config(ctx)?;
+    let cmd = auth_method
+        .helper_command()
+        .arg("config")
+        .args(command::helper_watch_args(cancel))
+        .arg("--output")
+        .arg(&plan.path)
+        .args(if let Some(generations) = config.get_generation_count() {
+            vec!["--generations".to_string(), generations.to_string()]
+        } else {
+            vec![]
+        })
+        .arg("--")
+        .arg("switch")
+        .args(progress::log_format_args(progress))
+        .args(match &plan.flake {
+            Some(flake) => vec!["--flake".to_string(), flake.clone()],
+            None => vec![],
+        })
+        .helper_input(Some(plan.new_config.clone()), cancel);
+    let output = progress::run(ctx.runner(), &cmd, progress).await?;
+    debug!("{:?}", output.code);
+    Error::check_auth_output(auth_method, &output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandOutput, FakeRunner},
+        config::configfile::LibXinuxConfig,
+        nixos::{install, remove},
+        HELPER_EXEC,
+    };
+    use std::sync::Arc;
+
+    const CONFIG: &str =
+        "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [\n    hello\n  ];\n}\n";
+
+    /// Context with a NixOS configuration in a flake, containing `hello`.
+    fn context(runner: &Arc<FakeRunner>, name: &str) -> (Context, String) {
+        let dir =
+            std::env::temp_dir().join(format!("libxinux-apply-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().to_string();
+        let ctx = Context::builder()
+            .runner(runner.clone())
+            .arch("x86_64-linux")
+            .home(format!("{}/home", dir))
+            .config_dir(format!("{}/config", dir))
+            .is_nixos(true)
+            .build()
+            .unwrap();
+        fs::write(format!("{}/configuration.nix", dir), CONFIG).unwrap();
+        fs::write(format!("{}/flake.nix", dir), "{ }").unwrap();
+        LibXinuxConfig {
+            systemconfig: Some(format!("{}/configuration.nix", dir)),
+            flake: Some(format!("{}/flake.nix", dir)),
+            host: Some("laptop".to_string()),
+            ..Default::default()
+        }
+        .write(&ctx)
+        .unwrap();
+        (ctx, dir)
+    }
+
+    fn database() -> rusqlite::Connection {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE pkgs (attribute TEXT, pname TEXT, version TEXT);
+             CREATE TABLE meta (attribute TEXT, description TEXT, long_description TEXT,
+                 broken INTEGER, insecure INTEGER, unfree INTEGER);
+             INSERT INTO pkgs VALUES ('hello', 'hello', '2.12.1'), ('vim', 'vim', '9.1');",
+        )
+        .unwrap();
+        db
+    }
+
+    /// Value passed to the helper with `--flake` when applying `plan`.
+    async fn flake_arg(ctx: &Context, runner: &FakeRunner, plan: &Plan) -> Option<String> {
+        apply(ctx, plan, AuthMethod::Pkexec, None, None)
+            .await
+            .unwrap();
+        let call = runner.calls().pop().unwrap();
+        call.args
+            .iter()
+            .position(|x| x == "--flake")
+            .map(|i| call.args[i + 1].clone())
+    }
+
+    #[tokio::test]
+    async fn install_switches_to_configured_host() {
+        let runner = Arc::new(FakeRunner::new().on(
+            "pkexec",
+            &[HELPER_EXEC],
+            CommandOutput::with_stdout(""),
+        ));
+        let (ctx, dir) = context(&runner, "install");
+        let plan = install::plan(&ctx, &["vim"], &database()).unwrap();
+        assert_eq!(
+            flake_arg(&ctx, &runner, &plan).await,
+            Some(format!("{}/flake.nix#laptop", dir))
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_switches_to_flake_dir() {
+        let runner = Arc::new(FakeRunner::new().on(
+            "pkexec",
+            &[HELPER_EXEC],
+            CommandOutput::with_stdout(""),
+        ));
+        let (ctx, dir) = context(&runner, "remove");
+        let plan = remove::plan(&ctx, &["hello"], &database()).unwrap();
+        assert_eq!(flake_arg(&ctx, &runner, &plan).await, Some(dir));
+    }
+}
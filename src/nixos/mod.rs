@@ -1,11 +1,71 @@
+pub mod apply;
 pub mod install;
 pub mod list;
 pub mod rebuild;
 pub mod remove;
 pub mod update;
 
+use crate::{
+    backend::PackageBackend, command::CommandSpec, Context, Package, PackageUpdate, Result,
+    HELPER_EXEC,
+};
+use async_trait::async_trait;
+
+#[derive(Debug, Clone, Copy)]
 pub enum AuthMethod<'a> {
     Pkexec,
     Sudo,
     Custom(&'a str),
 }
+
+impl AuthMethod<'_> {
+    /// Command running `libxinux-helper` with elevated privileges.
+    pub(crate) fn helper_command(&self) -> CommandSpec {
+        CommandSpec::new(match self {
+            AuthMethod::Pkexec => "pkexec",
+            AuthMethod::Sudo => "sudo",
+            AuthMethod::Custom(cmd) => cmd,
+        })
+        .arg(HELPER_EXEC)
+    }
+}
+
+/// [PackageBackend] managing `environment.systemPackages` in the NixOS configuration.
+pub struct NixosBackend<'a> {
+    ctx: &'a Context,
+    db: rusqlite::Connection,
+    auth_method: AuthMethod<'a>,
+}
+
+impl<'a> NixosBackend<'a> {
+    pub fn new(ctx: &'a Context, db: rusqlite::Connection, auth_method: AuthMethod<'a>) -> Self {
+        Self {
+            ctx,
+            db,
+            auth_method,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl PackageBackend for NixosBackend<'_> {
+    async fn list(&self) -> Result<Vec<Package>> {
+        list::list_systempackages(self.ctx, &self.db)
+    }
+
+    async fn install(&self, pkgs: &[&str]) -> Result<()> {
+        install::install(self.ctx, pkgs, &self.db, self.auth_method, None, None).await
+    }
+
+    async fn remove(&self, pkgs: &[&str]) -> Result<()> {
+        remove::remove(self.ctx, pkgs, &self.db, self.auth_method, None, None).await
+    }
+
+    async fn updatable(&self) -> Result<Vec<PackageUpdate>> {
+        update::updatable(self.ctx, &self.db).await
+    }
+
+    async fn update(&self, _pkgs: &[&str]) -> Result<()> {
+        update::update(self.ctx, self.auth_method, None, None).await
+    }
+}
@@ -1,25 +1,25 @@
-use super::AuthMethod;
-use crate::{config::configfile, nixos::list::list_systempackages, HELPER_EXEC};
-use anyhow::{anyhow, Context, Result};
+use super::{apply::apply, AuthMethod};
+use crate::{
+    command::CancellationToken,
+    config::{configfile, plan::Plan},
+    metadata::query,
+    nixos::list::list_systempackages,
+    progress::ProgressSender,
+    Context, Error, Result,
+};
 use log::debug;
-use tokio::io::AsyncWriteExt;
 
-pub async fn remove(
-    pkgs: &[&str],
-    db: &rusqlite::Connection,
-    auth_method: AuthMethod<'_>,
-) -> Result<()> {
-    let installed = list_systempackages(db)?
+/// Compute the configuration change removing `pkgs` would make, without applying it.
+pub fn plan(ctx: &Context, pkgs: &[&str], db: &rusqlite::Connection) -> Result<Plan> {
+    let installed = list_systempackages(ctx, db)?
         .into_iter()
         .map(|x| x.attr.to_string())
         .collect::<Vec<_>>();
 
     // Check if the package is within nixpkgs and if it is installed
-    let mut stmt = db.prepare("SELECT pname FROM pkgs WHERE attribute = ?")?;
     let mut pkgs_to_remove = vec![];
     for pkg in pkgs {
-        let out: Result<String, _> = stmt.query_row([pkg], |row| row.get(0));
-        if let Ok(_pname) = out {
+        if query::package(db, pkg)?.is_some() {
             if installed.contains(&pkg.to_string()) {
                 pkgs_to_remove.push(pkg.to_string());
             } else {
@@ -28,14 +28,14 @@ pub async fn remove(
         }
     }
 
-    // Install the packages
-    let config = configfile::get_config()?;
+    let config = configfile::get_config(ctx)?;
     let oldconfig = config.read_system_config_file()?;
 
     if pkgs_to_remove.is_empty() {
-        return Err(anyhow!("No new packages to install"));
+        return Err(Error::NothingToDo("No packages to remove".to_string()));
     }
 
+    let attrs = pkgs_to_remove.clone();
     if let Ok(withvals) = nix_editor::read::getwithvalue(&oldconfig, "environment.systemPackages") {
         if !withvals.contains(&String::from("pkgs")) {
             pkgs_to_remove = pkgs_to_remove
@@ -48,42 +48,21 @@ pub async fn remove(
     let newconfig =
         nix_editor::write::rmarr(&oldconfig, "environment.systemPackages", pkgs_to_remove)?;
 
-    let mut output = tokio::process::Command::new(match auth_method {
-        AuthMethod::Pkexec => "pkexec",
-        AuthMethod::Sudo => "sudo",
-        AuthMethod::Custom(cmd) => cmd,
-    })
-    .arg(HELPER_EXEC)
-    .arg("config")
-    .arg("--output")
-    .arg(
-        &config
-            .systemconfig
-            .clone()
-            .context("Failed to get system config path")?,
-    )
-    .args(if let Some(generations) = config.get_generation_count() {
-        vec!["--generations".to_string(), generations.to_string()]
-    } else {
-        vec![]
-    })
-    .arg("--")
-    .arg("switch")
-    .args(if let Ok(flakedir) = config.get_flake_dir() {
-        vec!["--flake".to_string(), flakedir]
-    } else {
-        vec![]
-    })
-    .stdin(std::process::Stdio::piped())
-    .spawn()?;
-    output
-        .stdin
-        .as_mut()
-        .ok_or("stdin not available")
-        .unwrap()
-        .write_all(newconfig.as_bytes())
-        .await?;
-    let output = output.wait().await?;
-    debug!("{}", output);
-    Ok(())
+    let flake = config.get_flake_dir().ok();
+    let path = config
+        .systemconfig
+        .ok_or_else(|| Error::Config("Failed to get system config path".to_string()))?;
+    Ok(Plan::new(path, oldconfig, newconfig, vec![], attrs, flake))
+}
+
+pub async fn remove(
+    ctx: &Context,
+    pkgs: &[&str],
+    db: &rusqlite::Connection,
+    auth_method: AuthMethod<'_>,
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let plan = plan(ctx, pkgs, db)?;
+    apply(ctx, &plan, auth_method, progress, cancel).await
 }
@@ -1,43 +1,48 @@
 use super::AuthMethod;
 use crate::{
-    config::configfile::get_config, nixos::list::list_systempackages, utils, PackageUpdate,
-    HELPER_EXEC,
+    command::{self, CancellationToken},
+    config::configfile::get_config,
+    nixos::list::list_systempackages,
+    progress::{self, ProgressSender},
+    utils, Context, Error, PackageUpdate, Result,
 };
-use anyhow::Result;
 use log::debug;
 
-pub async fn updatable(db: &rusqlite::Connection) -> Result<Vec<PackageUpdate>> {
-    utils::misc::updatable(list_systempackages(db)?).await
+pub async fn updatable(ctx: &Context, db: &rusqlite::Connection) -> Result<Vec<PackageUpdate>> {
+    utils::misc::updatable(ctx, list_systempackages(ctx, db)?).await
 }
 
-pub async fn update(auth_method: AuthMethod<'_>) -> Result<()> {
-    let config = get_config()?;
-    let output = tokio::process::Command::new(match auth_method {
-        AuthMethod::Pkexec => "pkexec",
-        AuthMethod::Sudo => "sudo",
-        AuthMethod::Custom(cmd) => cmd,
-    })
-    .arg(HELPER_EXEC)
-    .arg("update")
-    .args(if let Some(generations) = config.get_generation_count() {
-        vec!["--generations".to_string(), generations.to_string()]
-    } else {
-        vec![]
-    })
-    .args(if let Ok(flakedir) = config.get_flake_dir() {
-        vec!["--flake".to_string(), flakedir]
-    } else {
-        vec![]
-    })
-    .arg("--")
-    .arg("switch")
-    .args(if let Ok(flakedir) = config.get_flake_dir() {
-        vec!["--flake".to_string(), flakedir]
-    } else {
-        vec![]
-    })
-    .status()
-    .await?;
-    debug!("{}", output);
-    Ok(())
+pub async fn update(
+    ctx: &Context,
+    auth_method: AuthMethod<'_>,
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let config = get_config(ctx)?;
+    let cmd = auth_method
+        .helper_command()
+        .arg("update")
+        .args(command::helper_watch_args(cancel))
+        .args(if let Some(generations) = config.get_generation_count() {
+            vec!["--generations".to_string(), generations.to_string()]
+        } else {
+            vec![]
+        })
+        .args(if let Ok(flakedir) = config.get_flake_dir() {
+            vec!["--flake".to_string(), flakedir]
+        } else {
+            vec![]
+        })
+        .arg("--")
+        .arg("switch")
+        .args(progress::log_format_args(progress))
+        .args(if let Ok(flakedir) = config.get_flake_dir() {
+            vec!["--flake".to_string(), flakedir]
+        } else {
+            vec![]
+        })
+        .helper_input(None, cancel);
+    let output = progress::run(ctx.runner(), &cmd, progress).await?;
+    debug!("{:?}", output.code);
+    Error::check_auth_output(auth_method, &output)
 }
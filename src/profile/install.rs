@@ -1,15 +1,28 @@
-use crate::{profile::list::list, PackageAttr};
-use anyhow::{anyhow, Result};
-use tokio::process::Command;
+use crate::{
+    command::{CancellationToken, CommandSpec},
+    profile::list::list,
+    progress::{self, ProgressSender},
+    Context, Error, PackageAttr, Result,
+};
 
-pub async fn install(pkgs: &[&str]) -> Result<()> {
-    let installed = list()?;
+pub async fn install(
+    ctx: &Context,
+    pkgs: &[&str],
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let installed = list(ctx)?;
     let mut pkgs_to_install = Vec::new();
     for pkg in pkgs {
         if installed.iter().any(|x| match x.attr {
-            PackageAttr::NixPkgs { ref attr } => attr == pkg,
-            PackageAttr::External { ref attr, ref url } => {
-                (format!("{}#{}", url, attr) == *pkg) || (attr.ends_with(".default") && url == pkg)
+            PackageAttr::NixPkgs { ref attr, .. } => attr == pkg,
+            PackageAttr::External {
+                ref flake,
+                ref attr,
+                ..
+            } => {
+                x.attr.to_string() == *pkg
+                    || (attr.ends_with(".default") && flake.to_string() == *pkg)
             }
         }) {
             println!("Package {} is already installed", pkg);
@@ -19,28 +32,27 @@ pub async fn install(pkgs: &[&str]) -> Result<()> {
     }
 
     if pkgs_to_install.is_empty() {
-        return Err(anyhow!("No new packages to install"));
+        return Err(Error::NothingToDo("No new packages to install".to_string()));
     }
 
-    let status = Command::new("nix")
-        .arg("--extra-experimental-features")
-        .arg("nix-command flakes")
-        .arg("profile")
-        .arg("install")
-        .args(pkgs_to_install.iter().map(|x| {
-            if x.contains('#') || x.contains(':') {
-                x.to_string()
-            } else {
-                format!("nixpkgs#{}", x)
-            }
-        }))
-        .arg("--impure")
-        .status()
-        .await?;
-
-    if !status.success() {
-        Err(anyhow!("Failed to install packages"))
-    } else {
-        Ok(())
-    }
+    let output = progress::run(
+        ctx.runner(),
+        &CommandSpec::new("nix")
+            .arg("--extra-experimental-features")
+            .arg("nix-command flakes")
+            .arg("profile")
+            .arg("install")
+            .args(progress::log_format_args(progress))
+            .args(
+                pkgs_to_install
+                    .iter()
+                    .map(|x| Ok(x.parse::<PackageAttr>()?.to_installable()))
+                    .collect::<Result<Vec<_>>>()?,
+            )
+            .arg("--impure")
+            .cancel_on(cancel),
+        progress,
+    )
+    .await?;
+    Error::check_output("nix profile install", &output)
 }
@@ -1,5 +1,7 @@
-use crate::{utils::misc::get_pname_version_from_storepath, Package, PackageAttr, NIXARCH};
-use anyhow::{Context, Result};
+use crate::{
+    command::CommandSpec, utils::misc::get_pname_version_from_storepath, Context, Error, Package,
+    PackageAttr, Result,
+};
 use log::debug;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -19,13 +21,15 @@ struct ProfilePkgOut {
     storepaths: Vec<String>,
 }
 
-pub fn list() -> Result<Vec<Package>> {
+pub fn list(ctx: &Context) -> Result<Vec<Package>> {
     let profileroot: ProfilePkgsRoot = serde_json::from_reader(
-        std::process::Command::new("nix")
-            .arg("profile")
-            .arg("list")
-            .arg("--json")
-            .output()?
+        ctx.runner()
+            .run_blocking(
+                &CommandSpec::new("nix")
+                    .arg("profile")
+                    .arg("list")
+                    .arg("--json"),
+            )?
             .stdout
             .as_slice(),
     )?;
@@ -43,13 +47,13 @@ pub fn list() -> Result<Vec<Package>> {
 
             let (pname, version) = get_pname_version_from_storepath(&storepath)?;
 
-            if let Some(pkgattr) =
-                attrpath.strip_prefix(&format!("legacyPackages.{}.", NIXARCH.as_str()))
+            if let Some(pkgattr) = attrpath.strip_prefix(&format!("legacyPackages.{}.", ctx.arch()))
             {
                 pkgs.push(Package {
                     version,
                     attr: PackageAttr::NixPkgs {
                         attr: pkgattr.to_string(),
+                        outputs: None,
                     },
                     pname: Some(pname),
                     profile_name: Some(profile_name),
@@ -58,8 +62,9 @@ pub fn list() -> Result<Vec<Package>> {
                 pkgs.push(Package {
                     version,
                     attr: PackageAttr::External {
-                        url: originalurl,
+                        flake: originalurl.parse()?,
                         attr: attrpath,
+                        outputs: None,
                     },
                     pname: Some(pname),
                     profile_name: Some(profile_name),
@@ -71,26 +76,31 @@ pub fn list() -> Result<Vec<Package>> {
     Ok(pkgs)
 }
 
-pub fn name_from_attr(attr: &str) -> Result<String> {
-    let list = list()?;
+pub fn name_from_attr(ctx: &Context, attr: &str) -> Result<String> {
+    let list = list(ctx)?;
     for pkg in list {
         match pkg.attr {
-            PackageAttr::NixPkgs { attr: x } => {
+            PackageAttr::NixPkgs { attr: ref x, .. } => {
                 if x == attr {
-                    return pkg.profile_name.context("Profile name not found");
+                    return pkg
+                        .profile_name
+                        .ok_or_else(|| Error::PackageNotFound(attr.to_string()));
                 }
             }
             PackageAttr::External {
-                url,
-                attr: ext_attr,
+                ref flake,
+                attr: ref ext_attr,
+                ..
             } => {
-                if attr == format!("{}#{}", url, ext_attr)
-                    || (ext_attr.ends_with(".default") && url == attr)
+                if pkg.attr.to_string() == attr
+                    || (ext_attr.ends_with(".default") && flake.to_string() == attr)
                 {
-                    return pkg.profile_name.context("Profile name not found");
+                    return pkg
+                        .profile_name
+                        .ok_or_else(|| Error::PackageNotFound(attr.to_string()));
                 }
             }
         }
     }
-    Err(anyhow::anyhow!("Package not found"))
+    Err(Error::PackageNotFound(attr.to_string()))
 }
@@ -3,3 +3,44 @@ pub mod list;
 pub mod remove;
 pub mod run;
 pub mod update;
+
+use crate::{backend::PackageBackend, Context, Package, PackageUpdate, Result};
+use async_trait::async_trait;
+
+/// [PackageBackend] using the `nix profile` command.
+pub struct ProfileBackend<'a> {
+    ctx: &'a Context,
+}
+
+impl<'a> ProfileBackend<'a> {
+    pub fn new(ctx: &'a Context) -> Self {
+        Self { ctx }
+    }
+}
+
+#[async_trait(?Send)]
+impl PackageBackend for ProfileBackend<'_> {
+    async fn list(&self) -> Result<Vec<Package>> {
+        list::list(self.ctx)
+    }
+
+    async fn install(&self, pkgs: &[&str]) -> Result<()> {
+        install::install(self.ctx, pkgs, None, None).await
+    }
+
+    async fn remove(&self, pkgs: &[&str]) -> Result<()> {
+        remove::remove(self.ctx, pkgs, None, None).await
+    }
+
+    async fn updatable(&self) -> Result<Vec<PackageUpdate>> {
+        update::updatable(self.ctx).await
+    }
+
+    async fn update(&self, pkgs: &[&str]) -> Result<()> {
+        if pkgs.is_empty() {
+            update::update_all(self.ctx, None, None).await
+        } else {
+            update::update(self.ctx, pkgs, None, None).await
+        }
+    }
+}
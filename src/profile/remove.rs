@@ -1,23 +1,25 @@
 use crate::{
+    command::{CancellationToken, CommandSpec},
     profile::list::{list, name_from_attr},
-    PackageAttr,
+    progress::{self, ProgressSender},
+    Context, Error, Result,
 };
-use anyhow::{anyhow, Result};
 use log::debug;
-use tokio::process::Command;
 
-pub async fn remove(pkgs: &[&str]) -> Result<()> {
-    let list = list()?
+pub async fn remove(
+    ctx: &Context,
+    pkgs: &[&str],
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let list = list(ctx)?
         .into_iter()
-        .map(|x| match x.attr {
-            PackageAttr::NixPkgs { attr } => attr,
-            PackageAttr::External { url, attr } => format!("{}#{}", url, attr),
-        })
+        .map(|x| x.attr.to_string())
         .collect::<Vec<_>>();
     let mut pkgs_to_remove = Vec::new();
     for pkg in pkgs {
         if list.contains(&pkg.to_string()) {
-            if let Ok(name) = name_from_attr(pkg) {
+            if let Ok(name) = name_from_attr(ctx, pkg) {
                 pkgs_to_remove.push(name);
             }
         } else {
@@ -26,15 +28,20 @@ pub async fn remove(pkgs: &[&str]) -> Result<()> {
     }
 
     if pkgs_to_remove.is_empty() {
-        return Err(anyhow!("No packages to remove"));
+        return Err(Error::NothingToDo("No packages to remove".to_string()));
     }
 
-    let output = Command::new("nix")
-        .arg("profile")
-        .arg("remove")
-        .args(pkgs_to_remove)
-        .status()
-        .await?;
-    debug!("{}", output);
-    Ok(())
+    let output = progress::run(
+        ctx.runner(),
+        &CommandSpec::new("nix")
+            .arg("profile")
+            .arg("remove")
+            .args(progress::log_format_args(progress))
+            .args(pkgs_to_remove)
+            .cancel_on(cancel),
+        progress,
+    )
+    .await?;
+    debug!("{:?}", output.code);
+    Error::check_output("nix profile remove", &output)
 }
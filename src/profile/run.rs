@@ -1,25 +1,19 @@
-use anyhow::{anyhow, Result};
-use tokio::process::Command;
+use crate::{command::CommandSpec, Context, Error, PackageAttr, Result};
 
-pub async fn run(pkg: &str, args: &[&str]) -> Result<()> {
-    let status = Command::new("nix")
-        .arg("--extra-experimental-features")
-        .arg("nix-command flakes")
-        .arg("run")
-        .arg(if pkg.contains('#') || pkg.contains(':') {
-            pkg.to_string()
-        } else {
-            format!("nixpkgs#{}", pkg)
-        })
-        .arg("--impure")
-        .arg("--")
-        .args(args)
-        .status()
+pub async fn run(ctx: &Context, pkg: &str, args: &[&str]) -> Result<()> {
+    let output = ctx
+        .runner()
+        .run(
+            &CommandSpec::new("nix")
+                .arg("--extra-experimental-features")
+                .arg("nix-command flakes")
+                .arg("run")
+                .arg(pkg.parse::<PackageAttr>()?.to_installable())
+                .arg("--impure")
+                .arg("--")
+                .args(args.iter().copied())
+                .interactive(),
+        )
         .await?;
-
-    if !status.success() {
-        Err(anyhow!("Failed to install packages"))
-    } else {
-        Ok(())
-    }
+    Error::check_output("nix run", &output)
 }
@@ -1,63 +1,69 @@
 use crate::{
+    command::{CancellationToken, CommandSpec},
     profile::list::{list, name_from_attr},
-    utils, PackageAttr, PackageUpdate,
+    progress::{self, ProgressSender},
+    utils, Context, Error, PackageAttr, PackageUpdate, Result,
 };
-use anyhow::{anyhow, Result};
 use log::debug;
-use tokio::process::Command;
 
-pub async fn updatable() -> Result<Vec<PackageUpdate>> {
-    utils::misc::updatable(list()?).await
+pub async fn updatable(ctx: &Context) -> Result<Vec<PackageUpdate>> {
+    utils::misc::updatable(ctx, list(ctx)?).await
 }
 
-pub async fn updatable_all() -> Result<Vec<PackageUpdate>> {
-    let installed = list()?;
+pub async fn updatable_all(ctx: &Context) -> Result<Vec<PackageUpdate>> {
+    let installed = list(ctx)?;
     let mut updatable = vec![];
 
     for pkg in installed {
         debug!("Checking for updates: {:?}", pkg);
         match &pkg.attr {
-            PackageAttr::NixPkgs { attr } => {
-                let output = Command::new("nix")
-                    .arg("eval")
-                    .arg(format!("nixpkgs#{}.version", attr))
-                    .arg("--raw")
-                    .output()
+            PackageAttr::NixPkgs { attr, .. } => {
+                let output = ctx
+                    .runner()
+                    .run(
+                        &CommandSpec::new("nix")
+                            .arg("eval")
+                            .arg(format!("nixpkgs#{}.version", attr))
+                            .arg("--raw"),
+                    )
                     .await;
                 if let Ok(output) = output {
-                    let output = String::from_utf8(output.stdout)?;
+                    let output = output.stdout_string()?;
                     let version = output.trim();
 
                     if version.is_empty() {
                         continue;
                     } else if pkg.version.is_none() || version != pkg.version.as_ref().unwrap() {
-                        updatable.push(PackageUpdate {
-                            attr: pkg.attr.clone(),
-                            new_version: version.to_string(),
-                            old_version: pkg.version.unwrap_or_default(),
-                        });
+                        updatable.push(PackageUpdate::new(
+                            pkg.attr.clone(),
+                            pkg.version.unwrap_or_default(),
+                            version.to_string(),
+                        ));
                     }
                 }
             }
-            PackageAttr::External { url, attr } => {
-                let output = Command::new("nix")
-                    .arg("eval")
-                    .arg(format!("{}#{}.version", url, attr))
-                    .arg("--raw")
-                    .output()
+            PackageAttr::External { flake, attr, .. } => {
+                let output = ctx
+                    .runner()
+                    .run(
+                        &CommandSpec::new("nix")
+                            .arg("eval")
+                            .arg(format!("{}#{}.version", flake, attr))
+                            .arg("--raw"),
+                    )
                     .await;
                 if let Ok(output) = output {
-                    let output = String::from_utf8(output.stdout)?;
+                    let output = output.stdout_string()?;
                     let version = output.trim();
 
                     if version.is_empty() {
                         continue;
                     } else if pkg.version.is_none() || version != pkg.version.as_ref().unwrap() {
-                        updatable.push(PackageUpdate {
-                            attr: pkg.attr.clone(),
-                            new_version: version.to_string(),
-                            old_version: pkg.version.unwrap_or_default(),
-                        });
+                        updatable.push(PackageUpdate::new(
+                            pkg.attr.clone(),
+                            pkg.version.unwrap_or_default(),
+                            version.to_string(),
+                        ));
                     }
                 }
             }
@@ -66,15 +72,20 @@ pub async fn updatable_all() -> Result<Vec<PackageUpdate>> {
     Ok(updatable)
 }
 
-pub async fn update(pkgs: &[&str]) -> Result<()> {
-    let list = list()?
+pub async fn update(
+    ctx: &Context,
+    pkgs: &[&str],
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let list = list(ctx)?
         .into_iter()
         .map(|x| x.attr.to_string())
         .collect::<Vec<_>>();
     let mut pkgs_to_update = Vec::new();
     for pkg in pkgs {
         if list.contains(&pkg.to_string()) {
-            if let Ok(name) = name_from_attr(pkg) {
+            if let Ok(name) = name_from_attr(ctx, pkg) {
                 pkgs_to_update.push(name);
             }
         } else {
@@ -83,26 +94,40 @@ pub async fn update(pkgs: &[&str]) -> Result<()> {
     }
 
     if pkgs_to_update.is_empty() {
-        return Err(anyhow!("No packages to update"));
+        return Err(Error::NothingToDo("No packages to update".to_string()));
     }
 
-    let output = Command::new("nix")
-        .arg("profile")
-        .arg("upgrade")
-        .args(pkgs_to_update)
-        .status()
-        .await?;
-    debug!("{}", output);
-    Ok(())
+    let output = progress::run(
+        ctx.runner(),
+        &CommandSpec::new("nix")
+            .arg("profile")
+            .arg("upgrade")
+            .args(progress::log_format_args(progress))
+            .args(pkgs_to_update)
+            .cancel_on(cancel),
+        progress,
+    )
+    .await?;
+    debug!("{:?}", output.code);
+    Error::check_output("nix profile upgrade", &output)
 }
 
-pub async fn update_all() -> Result<()> {
-    let output = Command::new("nix")
-        .arg("profile")
-        .arg("upgrade")
-        .arg("--all")
-        .status()
-        .await?;
-    debug!("{}", output);
-    Ok(())
+pub async fn update_all(
+    ctx: &Context,
+    progress: Option<&ProgressSender>,
+    cancel: Option<&CancellationToken>,
+) -> Result<()> {
+    let output = progress::run(
+        ctx.runner(),
+        &CommandSpec::new("nix")
+            .arg("profile")
+            .arg("upgrade")
+            .args(progress::log_format_args(progress))
+            .arg("--all")
+            .cancel_on(cancel),
+        progress,
+    )
+    .await?;
+    debug!("{:?}", output.code);
+    Error::check_output("nix profile upgrade", &output)
 }
@@ -0,0 +1,129 @@
+//! # Progress
+//!
+//! Live progress reporting for long-running operations. Events are parsed from the
+//! `--log-format internal-json` output of Nix and the phase markers printed by
+//! `libxinux-helper`, and sent over a [ProgressSender].
+
+mod parser;
+
+pub use parser::NixLogParser;
+
+use crate::{
+    command::{CommandOutput, CommandRunner, CommandSpec},
+    Result,
+};
+use std::fmt;
+use tokio::sync::mpsc;
+
+/// Sending half of a progress event channel.
+pub type ProgressSender = mpsc::UnboundedSender<ProgressEvent>;
+/// Receiving half of a progress event channel.
+pub type ProgressReceiver = mpsc::UnboundedReceiver<ProgressEvent>;
+
+/// Create a channel for receiving [ProgressEvent]s.
+pub fn channel() -> (ProgressSender, ProgressReceiver) {
+    mpsc::unbounded_channel()
+}
+
+/// A step of an operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// The new configuration file is being written.
+    WritingConfig,
+    /// `nix flake update` is running.
+    UpdatingFlake,
+    /// `nixos-rebuild` or `home-manager` is running.
+    Rebuilding,
+    /// Old generations are being deleted.
+    DeletingGenerations,
+    /// The previous configuration file is being restored after a failure.
+    RestoringConfig,
+    /// A phase not known to this version of libxinux.
+    Other(String),
+}
+
+impl From<&str> for Phase {
+    fn from(s: &str) -> Self {
+        match s {
+            "write-config" => Phase::WritingConfig,
+            "update-flake" => Phase::UpdatingFlake,
+            "rebuild" => Phase::Rebuilding,
+            "delete-generations" => Phase::DeletingGenerations,
+            "restore-config" => Phase::RestoringConfig,
+            x => Phase::Other(x.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::WritingConfig => write!(f, "write-config"),
+            Phase::UpdatingFlake => write!(f, "update-flake"),
+            Phase::Rebuilding => write!(f, "rebuild"),
+            Phase::DeletingGenerations => write!(f, "delete-generations"),
+            Phase::RestoringConfig => write!(f, "restore-config"),
+            Phase::Other(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// Counters reported by Nix for a group of builds or downloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub done: u64,
+    pub expected: u64,
+    pub running: u64,
+    pub failed: u64,
+}
+
+/// An event emitted while an operation is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The operation entered a new phase.
+    Phase(Phase),
+    /// Progress of derivations being built.
+    Builds(Counts),
+    /// Progress of store paths being downloaded from a substituter.
+    Downloads(Counts),
+    /// A human readable log line.
+    Log(String),
+}
+
+/// Extra arguments making Nix print structured logs when `progress` is set.
+pub(crate) fn log_format_args(progress: Option<&ProgressSender>) -> Vec<&'static str> {
+    if progress.is_some() {
+        vec!["--log-format", "internal-json"]
+    } else {
+        vec![]
+    }
+}
+
+/// Run `cmd`, sending progress events to `progress` if it is set.
+///
+/// The standard error of the returned output only contains the human readable
+/// messages, so it can still be used for [CommandFailed](crate::Error::CommandFailed).
+pub(crate) async fn run(
+    runner: &dyn CommandRunner,
+    cmd: &CommandSpec,
+    progress: Option<&ProgressSender>,
+) -> Result<CommandOutput> {
+    let Some(progress) = progress else {
+        return runner.run(cmd).await;
+    };
+    let mut parser = NixLogParser::new();
+    let mut on_line = |line: &str| {
+        for event in parser.feed(line) {
+            // A dropped receiver only means nobody is listening anymore
+            let _ = progress.send(event);
+        }
+    };
+    let mut output = runner.run_streaming(cmd, &mut on_line).await?;
+    // Both streams went through `parser`, but only standard error holds messages
+    let mut stderr = NixLogParser::new();
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        stderr.feed(line);
+    }
+    output.stderr = stderr.messages().join("\n").into_bytes();
+    Ok(output)
+}
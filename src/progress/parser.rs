@@ -0,0 +1,123 @@
+use super::{Counts, Phase, ProgressEvent};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Prefix of structured log lines printed by `nix --log-format internal-json`.
+const NIX_PREFIX: &str = "@nix ";
+/// Prefix of phase markers printed by `libxinux-helper`.
+const HELPER_PREFIX: &str = "@libxinux ";
+
+// Activity and result types from Nix's `logging.hh`
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_PROGRESS: u64 = 105;
+const RES_POST_BUILD_LOG_LINE: u64 = 107;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum NixLogLine {
+    Msg {
+        msg: String,
+    },
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        activity: u64,
+        #[serde(default)]
+        text: String,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        result: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct HelperLogLine {
+    phase: String,
+}
+
+/// Turns the output of Nix and `libxinux-helper` into [ProgressEvent]s, line by line.
+#[derive(Debug, Default)]
+pub struct NixLogParser {
+    activities: HashMap<u64, u64>,
+    messages: Vec<String>,
+}
+
+impl NixLogParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a single line of output.
+    pub fn feed(&mut self, line: &str) -> Vec<ProgressEvent> {
+        if let Some(json) = line.strip_prefix(HELPER_PREFIX) {
+            return match serde_json::from_str::<HelperLogLine>(json) {
+                Ok(x) => vec![ProgressEvent::Phase(Phase::from(x.phase.as_str()))],
+                Err(_) => vec![],
+            };
+        }
+        let Some(json) = line.strip_prefix(NIX_PREFIX) else {
+            self.messages.push(line.to_string());
+            return vec![ProgressEvent::Log(line.to_string())];
+        };
+        match serde_json::from_str::<NixLogLine>(json) {
+            Ok(NixLogLine::Msg { msg }) => {
+                self.messages.push(msg.clone());
+                vec![ProgressEvent::Log(msg)]
+            }
+            Ok(NixLogLine::Start { id, activity, text }) => {
+                self.activities.insert(id, activity);
+                if text.is_empty() {
+                    vec![]
+                } else {
+                    vec![ProgressEvent::Log(text)]
+                }
+            }
+            Ok(NixLogLine::Stop { id }) => {
+                self.activities.remove(&id);
+                vec![]
+            }
+            Ok(NixLogLine::Result { id, result, fields }) => match result {
+                RES_BUILD_LOG_LINE | RES_POST_BUILD_LOG_LINE => fields
+                    .first()
+                    .and_then(Value::as_str)
+                    .map(|x| vec![ProgressEvent::Log(x.to_string())])
+                    .unwrap_or_default(),
+                RES_PROGRESS => {
+                    let counts = counts(&fields);
+                    match self.activities.get(&id) {
+                        Some(&ACT_BUILDS) => vec![ProgressEvent::Builds(counts)],
+                        Some(&ACT_COPY_PATHS) => vec![ProgressEvent::Downloads(counts)],
+                        _ => vec![],
+                    }
+                }
+                _ => vec![],
+            },
+            Err(_) => vec![],
+        }
+    }
+
+    /// Human readable messages seen so far, without build logs and progress updates.
+    pub fn messages(&self) -> &[String] {
+        &self.messages
+    }
+}
+
+fn counts(fields: &[Value]) -> Counts {
+    let field = |i: usize| fields.get(i).and_then(Value::as_u64).unwrap_or_default();
+    Counts {
+        done: field(0),
+        expected: field(1),
+        running: field(2),
+        failed: field(3),
+    }
+}
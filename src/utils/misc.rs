@@ -1,17 +1,47 @@
 use crate::{
-    metadata::{database::DatabaseCacheEntry, revision::get_latest_nixpkgs_revision},
-    Package, PackageAttr, PackageUpdate, ICON_UPDATER_EXEC,
+    command::CommandSpec,
+    metadata::{
+        database::DatabaseCacheEntry,
+        query::{self, PackageInfo},
+        revision::get_latest_nixpkgs_revision,
+    },
+    Context, Error, Package, PackageAttr, PackageUpdate, Result, ICON_UPDATER_EXEC,
 };
-use anyhow::{Context, Result};
-use log::debug;
+use log::{debug, warn};
+use std::future::Future;
+
+/// Run `request` against each of `urls` in order and return the first success.
+///
+/// Failures are logged before moving on to the next URL, and the last error is returned
+/// if every URL fails.
+pub(crate) async fn with_failover<'a, T, F, Fut>(urls: &'a [String], mut request: F) -> Result<T>
+where
+    F: FnMut(&'a str) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut last_err = None;
+    for url in urls {
+        match request(url).await {
+            Ok(x) => return Ok(x),
+            Err(err) => {
+                warn!("Request to {} failed: {}", url, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| Error::Config("No URLs configured".to_string())))
+}
 
 pub fn get_name_from_storepath(path: &str) -> Result<String> {
-    let name = path.split("/").last().context("No name found")?;
+    let name = path
+        .split('/')
+        .next_back()
+        .ok_or_else(|| Error::InvalidStorePath(path.to_string()))?;
     let name = name.split("-").skip(1).collect::<Vec<_>>().join("-");
     Ok(name)
 }
 
-fn get_pname_version(name: &str) -> Result<(String, Option<String>)> {
+pub(crate) fn get_pname_version(name: &str) -> Result<(String, Option<String>)> {
     let parts: std::str::Split<&str> = name.split("-");
     let index = parts
         .clone()
@@ -46,7 +76,7 @@ pub fn get_pname_from_storepath(path: &str, version: Option<String>) -> Result<S
     // hello-1.2.3 -> hello: where version="1.2.3"
     let name = if let Some(version) = version {
         name.strip_suffix(format!("-{}", version).as_str())
-            .context("No name found")?
+            .ok_or_else(|| Error::InvalidStorePath(path.to_string()))?
     } else {
         &name
     };
@@ -58,24 +88,22 @@ pub fn get_version_from_storepath(path: &str, pname: &str) -> Result<String> {
     // hello-1.2.3 -> 1.2.3: where pname="hello"
     let version = name
         .strip_prefix(&format!("{}-", pname))
-        .context("No version found")?;
+        .ok_or_else(|| Error::InvalidStorePath(path.to_string()))?;
     Ok(version.to_string())
 }
 
-pub async fn updatable(installed: Vec<Package>) -> Result<Vec<PackageUpdate>> {
+pub async fn updatable(ctx: &Context, installed: Vec<Package>) -> Result<Vec<PackageUpdate>> {
     let mut updatable = vec![];
-    let newrev = get_latest_nixpkgs_revision().await?;
-    let newdb = rusqlite::Connection::open(
-        crate::metadata::database::fetch_database(&newrev, DatabaseCacheEntry::New).await?,
+    let newrev = get_latest_nixpkgs_revision(ctx).await?;
+    let newdb = crate::metadata::schema::open(
+        &crate::metadata::database::fetch_database(ctx, &newrev, DatabaseCacheEntry::New, None)
+            .await?,
     )?;
-    let mut stmt = newdb.prepare("SELECT pname, version FROM pkgs WHERE attribute = ?")?;
 
     for pkg in installed {
         match &pkg.attr {
-            PackageAttr::NixPkgs { attr } => {
-                let out: Result<(String, String), _> =
-                    stmt.query_row([attr], |row| Ok((row.get(0)?, row.get(1)?)));
-                if let Ok((pname, version)) = out {
+            PackageAttr::NixPkgs { attr, .. } => {
+                if let Some(PackageInfo { pname, version, .. }) = query::package(&newdb, attr)? {
                     if let Ok((_pname, Some(version))) =
                         get_pname_version(&format!("{}-{}", pname, version))
                     {
@@ -89,23 +117,25 @@ pub async fn updatable(installed: Vec<Package>) -> Result<Vec<PackageUpdate>> {
                             continue;
                         } else if pkg.version.is_none() || version != *pkg.version.as_ref().unwrap()
                         {
-                            updatable.push(PackageUpdate {
-                                attr: pkg.attr.clone(),
-                                new_version: version.to_string(),
-                                old_version: pkg.version.unwrap_or_default(),
-                            });
+                            updatable.push(PackageUpdate::new(
+                                pkg.attr.clone(),
+                                pkg.version.unwrap_or_default(),
+                                version.to_string(),
+                            ));
                         }
                     }
                 }
             }
-            PackageAttr::External { url: _, attr: _ } => {}
+            PackageAttr::External { .. } => {}
         }
     }
     Ok(updatable)
 }
 
-pub fn refresh_icons() -> Result<()> {
-    let output = std::process::Command::new(ICON_UPDATER_EXEC).output()?;
-    debug!("{}", String::from_utf8(output.stdout)?);
+pub fn refresh_icons(ctx: &Context) -> Result<()> {
+    let output = ctx
+        .runner()
+        .run_blocking(&CommandSpec::new(ICON_UPDATER_EXEC))?;
+    debug!("{}", output.stdout_string()?);
     Ok(())
 }
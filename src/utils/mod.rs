@@ -4,3 +4,4 @@
 
 pub mod misc;
 pub mod storedb;
+pub mod version;
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use crate::{config::configfile::storebatch_urls, utils::misc::with_failover, Context, Result};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize)]
@@ -29,15 +29,22 @@ impl BatchStoreResponse {
     }
 }
 
-pub async fn get_storebatch(stores: Vec<&str>) -> Result<BatchStoreResponse> {
-    Ok(reqwest::Client::new()
-        .post("https://api.snowflakeos.org/v0/storebatch")
-        .json(&BatchStoreRequest {
-            stores,
-            includestore: true,
-        })
-        .send()
-        .await?
-        .json::<BatchStoreResponse>()
-        .await?)
+pub async fn get_storebatch(ctx: &Context, stores: Vec<&str>) -> Result<BatchStoreResponse> {
+    let client = reqwest::Client::new();
+    let body = BatchStoreRequest {
+        stores,
+        includestore: true,
+    };
+    with_failover(&storebatch_urls(ctx), |url| {
+        let request = client.post(url).json(&body);
+        async move {
+            Ok(request
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<BatchStoreResponse>()
+                .await?)
+        }
+    })
+    .await
 }
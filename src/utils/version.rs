@@ -0,0 +1,130 @@
+//! Version ordering following Nix's `builtins.compareVersions`.
+
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, convert::Infallible, fmt, str::FromStr};
+
+/// A package version compared the way Nix compares versions.
+///
+/// Versions are split into components at `.` and `-`, and between runs of digits and
+/// other characters. Numeric components compare as numbers, `pre` sorts before anything
+/// else, and a missing component sorts before a number, so `1.0 < 1.0.1` and
+/// `2.3a < 2.3.1`.
+///
+/// ```
+/// use libxinux::utils::version::NixVersion;
+///
+/// assert!(NixVersion::from("1.10") > NixVersion::from("1.9"));
+/// assert!(NixVersion::from("2.0pre1") < NixVersion::from("2.0"));
+/// assert!(NixVersion::from("unstable-2024-01-01") < NixVersion::from("2024-01-01"));
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NixVersion(String);
+
+impl NixVersion {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for NixVersion {
+    fn from(s: &str) -> Self {
+        NixVersion(s.to_string())
+    }
+}
+
+impl From<String> for NixVersion {
+    fn from(s: String) -> Self {
+        NixVersion(s)
+    }
+}
+
+impl FromStr for NixVersion {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.into())
+    }
+}
+
+impl fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl PartialEq for NixVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NixVersion {}
+
+impl PartialOrd for NixVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NixVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_versions(&self.0, &other.0)
+    }
+}
+
+/// Return the next component of `s` and the remaining input, or an empty component at the end.
+fn next_component(s: &str) -> (&str, &str) {
+    let s = s.trim_start_matches(['.', '-']);
+    let Some(first) = s.chars().next() else {
+        return ("", "");
+    };
+    let end = if first.is_ascii_digit() {
+        s.find(|c: char| !c.is_ascii_digit())
+    } else {
+        s.find(|c: char| c.is_ascii_digit() || c == '.' || c == '-')
+    }
+    .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn is_number(c: &str) -> bool {
+    !c.is_empty() && c.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Compare two numeric components without overflowing on long digit runs.
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+fn component_less(c1: &str, c2: &str) -> bool {
+    match (is_number(c1), is_number(c2)) {
+        (true, true) => compare_numbers(c1, c2) == Ordering::Less,
+        _ if c1.is_empty() && is_number(c2) => true,
+        _ if c1 == "pre" && c2 != "pre" => true,
+        _ if c2 == "pre" => false,
+        // `2.3a` < `2.3.1`
+        (_, true) => true,
+        (true, _) => false,
+        _ => c1 < c2,
+    }
+}
+
+/// Compare two versions like `builtins.compareVersions`.
+pub fn compare_versions(v1: &str, v2: &str) -> Ordering {
+    let (mut v1, mut v2) = (v1, v2);
+    while !v1.is_empty() || !v2.is_empty() {
+        let (c1, rest1) = next_component(v1);
+        let (c2, rest2) = next_component(v2);
+        if component_less(c1, c2) {
+            return Ordering::Less;
+        }
+        if component_less(c2, c1) {
+            return Ordering::Greater;
+        }
+        (v1, v2) = (rest1, rest2);
+    }
+    Ordering::Equal
+}